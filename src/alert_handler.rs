@@ -0,0 +1,240 @@
+use axum::{extract::{Path, State}, http::StatusCode, Json};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::discord::send_message_to_channel;
+use crate::enums::Category;
+use crate::models::{Alert, AlertDirection, AlertRequest, ApiResponse};
+use crate::ninja_handler::{internal_error, parse_category};
+use crate::store::Store;
+use crate::AppState;
+
+const ALERT_KEY: &str = "Alerts";
+
+// Public functions
+// List all alerts
+pub async fn list_alerts(State(state): State<AppState>) -> Result<(StatusCode, Json<Vec<Alert>>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let alerts = get_all_alerts(store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(alerts)))
+}
+
+// Get an alert by id
+pub async fn get_alert(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<Alert>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let alert = find_alert(&id, store).await?;
+    Ok((StatusCode::OK, Json(alert)))
+}
+
+// Create an alert
+pub async fn create_alert(State(state): State<AppState>, Json(payload): Json<AlertRequest>) -> Result<(StatusCode, Json<Alert>), (StatusCode, Json<Value>)> {
+    validate_request(&payload)?;
+    let store = state.store.as_ref();
+    let alert = build_alert(Uuid::new_v4().to_string(), payload);
+    store.push_hash(ALERT_KEY, &alert.id, &alert.to_json_string()).await.map_err(internal_error)?;
+    tracing::info!("Alert {} added: {} {:?} {}", alert.id, alert.name, alert.direction, alert.price);
+    Ok((StatusCode::CREATED, Json(alert)))
+}
+
+// Replace an alert, the crossing state is reset
+pub async fn update_alert(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<AlertRequest>,
+) -> Result<(StatusCode, Json<Alert>), (StatusCode, Json<Value>)> {
+    validate_request(&payload)?;
+    let store = state.store.as_ref();
+    find_alert(&id, store).await?;
+    let alert = build_alert(id, payload);
+    store.push_hash(ALERT_KEY, &alert.id, &alert.to_json_string()).await.map_err(internal_error)?;
+    tracing::info!("Alert {} updated", alert.id);
+    Ok((StatusCode::OK, Json(alert)))
+}
+
+// Delete an alert
+pub async fn delete_alert(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<Alert>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let alert = find_alert(&id, store).await?;
+    store.remove_hash(ALERT_KEY, &id).await.map_err(internal_error)?;
+    tracing::info!("Alert {} deleted", id);
+    Ok((StatusCode::OK, Json(alert)))
+}
+
+// Evaluate the alerts of a category against a fresh response and send a message on every crossing
+// The new state is only written over the alert that was read, so a concurrent update or delete wins
+pub async fn evaluate_alerts(league: &str, category: Category, api_response: &ApiResponse, store: &dyn Store) {
+    let alerts = match store.get_all_hash(ALERT_KEY).await {
+        Ok(alerts) => alerts,
+        Err(e) => {
+            tracing::error!("Get alerts error: {}", e);
+            return;
+        }
+    };
+    let prices = get_prices(api_response);
+    let now = chrono::offset::Utc::now().timestamp();
+    for (id, value) in alerts {
+        let mut alert = match serde_json::from_str::<Alert>(&value) {
+            Ok(alert) => alert,
+            Err(e) => {
+                tracing::error!("Parse alert {} error: {}", id, e);
+                continue;
+            }
+        };
+        if alert.league != league || alert.category != category.as_str() {
+            continue;
+        }
+        let price = match prices.iter().find(|(name, _)| *name == alert.name) {
+            Some((_, price)) => *price,
+            None => continue,
+        };
+        let fired = check_crossing(&mut alert, price, now);
+        match store.set_hash_if_equal(ALERT_KEY, &id, &value, &alert.to_json_string()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::info!("Alert {} changed during the evaluation, skip it", id);
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("Save alert {} error: {}", id, e);
+                continue;
+            }
+        }
+        if fired {
+            tracing::info!("Alert {} fired: {} {}", alert.id, alert.name, price);
+            if let Err(e) = send_message_to_channel(format_alert_message(&alert, price)).await {
+                tracing::error!("Alert {} message error: {}", alert.id, e);
+            }
+        }
+    }
+}
+
+// Private functions
+// Update the alert state with a new price, return true if it should fire
+fn check_crossing(alert: &mut Alert, price: f64, now: i64) -> bool {
+    let state = match alert.direction {
+        AlertDirection::Above => price > alert.price,
+        AlertDirection::Below => price < alert.price,
+    };
+    let crossed = alert.last_state == Some(false) && state;
+    let cooled_down = match alert.last_fired {
+        Some(last_fired) => now - last_fired >= alert.cooldown_secs,
+        None => true,
+    };
+    alert.last_state = Some(state);
+    alert.last_price = Some(price);
+    if crossed && cooled_down {
+        alert.last_fired = Some(now);
+        return true;
+    }
+    false
+}
+
+fn format_alert_message(alert: &Alert, price: f64) -> String {
+    let direction = match alert.direction {
+        AlertDirection::Above => "above",
+        AlertDirection::Below => "below",
+    };
+    format!(
+        "# Alert\n- **{}** ({}) is {} {} chaos\n  - Chaos: {}\n",
+        alert.name, alert.category, direction, alert.price, price
+    )
+}
+
+// Chaos price of every line in the response
+fn get_prices(api_response: &ApiResponse) -> Vec<(&str, f64)> {
+    let mut prices = Vec::new();
+    if let Some(currency_response) = &api_response.currency_response {
+        for line in currency_response.lines.iter() {
+            prices.push((line.currencyTypeName.as_str(), line.chaosEquivalent));
+        }
+    }
+    if let Some(item_response) = &api_response.item_response {
+        for line in item_response.lines.iter() {
+            prices.push((line.name.as_str(), line.chaosValue));
+        }
+    }
+    prices
+}
+
+async fn get_all_alerts(store: &dyn Store) -> redis::RedisResult<Vec<Alert>> {
+    let mut alerts = Vec::new();
+    for (id, value) in store.get_all_hash(ALERT_KEY).await? {
+        match serde_json::from_str::<Alert>(&value) {
+            Ok(alert) => alerts.push(alert),
+            Err(e) => tracing::error!("Parse alert {} error: {}", id, e),
+        }
+    }
+    Ok(alerts)
+}
+
+async fn find_alert(id: &str, store: &dyn Store) -> Result<Alert, (StatusCode, Json<Value>)> {
+    let value = match store.get_hash(ALERT_KEY, id).await {
+        Ok(value) => value,
+        // a missing field is a type error in every store, like HGET of nil in Redis
+        Err(e) if e.kind() == redis::ErrorKind::TypeError => {
+            return Err((StatusCode::NOT_FOUND, Json(json!({ "error": format!("Alert {} not found", id) }))));
+        }
+        Err(e) => return Err(internal_error(e)),
+    };
+    serde_json::from_str(&value).map_err(|e| {
+        tracing::error!("Parse alert {} error: {}", id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Alert {} is corrupted", id) })))
+    })
+}
+
+fn validate_request(payload: &AlertRequest) -> Result<(), (StatusCode, Json<Value>)> {
+    parse_category(&payload.category)?;
+    if payload.price <= 0.0 || payload.cooldown_secs < 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "price must be positive and cooldown_secs not negative" })),
+        ));
+    }
+    Ok(())
+}
+
+fn build_alert(id: String, payload: AlertRequest) -> Alert {
+    Alert {
+        id,
+        league: payload.league,
+        category: payload.category,
+        name: payload.name,
+        direction: payload.direction,
+        price: payload.price,
+        cooldown_secs: payload.cooldown_secs,
+        last_state: None,
+        last_price: None,
+        last_fired: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_crossing() {
+        let mut alert = build_alert(
+            String::from("test"),
+            AlertRequest {
+                league: String::from("Affliction"),
+                category: String::from("Currency"),
+                name: String::from("Divine Orb"),
+                direction: AlertDirection::Above,
+                price: 250.0,
+                cooldown_secs: 600,
+            },
+        );
+        // First evaluation only records the state
+        assert!(!check_crossing(&mut alert, 260.0, 0));
+        assert!(!check_crossing(&mut alert, 240.0, 60));
+        assert!(check_crossing(&mut alert, 255.0, 120));
+        // Staying above does not fire again
+        assert!(!check_crossing(&mut alert, 258.0, 180));
+        // Crossing again inside the cooldown is swallowed
+        assert!(!check_crossing(&mut alert, 240.0, 240));
+        assert!(!check_crossing(&mut alert, 251.0, 300));
+        assert!(!check_crossing(&mut alert, 249.0, 720));
+        assert!(check_crossing(&mut alert, 252.0, 780));
+    }
+}
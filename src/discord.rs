@@ -26,7 +26,7 @@ struct Discord {
 #[async_trait]
 impl EventHandler for Handler {}
 // Public methods
-pub async fn send_message_to_channel(message: String) {
+pub async fn send_message_to_channel(message: String) -> Result<(), String> {
     let profile = get_profile();
     let config = get_config_from_toml(&profile);
     let token = config.api_key;
@@ -37,13 +37,12 @@ pub async fn send_message_to_channel(message: String) {
     match res {
         Ok(_) => {
             tracing::info!("Message sent");
-            println!("Message sent");
+            Ok(())
         }
         Err(e) => {
             tracing::error!("Message failed to send: {}", e);
-            println!("Message failed to send: {}", e);
+            Err(e.to_string())
         }
-    
     }
 }
 
@@ -59,5 +58,5 @@ fn get_config_from_toml(profile: &str) -> Discord {
 #[cfg(test)]
 #[tokio::test]
 async fn test_send_message_to_channel() {
-    send_message_to_channel("~~Fuck you~~".to_string()).await;
+    let _ = send_message_to_channel("~~Fuck you~~".to_string()).await;
 }
\ No newline at end of file
@@ -1,20 +1,214 @@
+use std::fmt;
+use std::str::FromStr;
 
-pub enum league {
-    Standard,
-    Affliction
+// Endpoint family of poe.ninja, also the first layer of the Redis keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MainCategory {
+    // For currencyoverview
+    Currency,
+    // For itemoverview
+    Item,
 }
 
-// For currencyoverview
-pub enum category_of_currency {
-    Currency,
-    Fragment
+impl MainCategory {
+    pub const ALL: [MainCategory; 2] = [MainCategory::Currency, MainCategory::Item];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MainCategory::Currency => "Currency",
+            MainCategory::Item => "Item",
+        }
+    }
+
+    pub fn endpoint(&self) -> &'static str {
+        match self {
+            MainCategory::Currency => "currencyoverview",
+            MainCategory::Item => "itemoverview",
+        }
+    }
+
+    // All valid main category names, used in error responses
+    pub fn names() -> Vec<&'static str> {
+        MainCategory::ALL.iter().map(|c| c.as_str()).collect()
+    }
 }
 
-// For itemoverview
-pub enum category_of_item {
+impl FromStr for MainCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MainCategory, String> {
+        MainCategory::ALL
+            .iter()
+            .find(|c| c.as_str() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown main category: {}", s))
+    }
+}
+
+impl fmt::Display for MainCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Every overview type poe.ninja exposes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Currency,
+    Fragment,
+    Oil,
+    Incubator,
     Scarab,
-    Essense,
+    Fossil,
+    Resonator,
+    Essence,
     DivinationCard,
+    SkillGem,
+    BaseType,
+    HelmetEnchant,
+    UniqueMap,
+    Map,
+    UniqueJewel,
+    UniqueFlask,
+    UniqueWeapon,
+    UniqueArmour,
     UniqueAccessory,
-    UniqueJewel
-}
\ No newline at end of file
+    Beast,
+    Vial,
+    DeliriumOrb,
+    Omen,
+    UniqueRelic,
+    ClusterJewel,
+    BlightedMap,
+    BlightRavagedMap,
+    Invitation,
+    Memory,
+    Coffin,
+    AllflameEmber,
+    Tattoo,
+    Artifact,
+}
+
+impl Category {
+    pub const ALL: [Category; 33] = [
+        Category::Currency,
+        Category::Fragment,
+        Category::Oil,
+        Category::Incubator,
+        Category::Scarab,
+        Category::Fossil,
+        Category::Resonator,
+        Category::Essence,
+        Category::DivinationCard,
+        Category::SkillGem,
+        Category::BaseType,
+        Category::HelmetEnchant,
+        Category::UniqueMap,
+        Category::Map,
+        Category::UniqueJewel,
+        Category::UniqueFlask,
+        Category::UniqueWeapon,
+        Category::UniqueArmour,
+        Category::UniqueAccessory,
+        Category::Beast,
+        Category::Vial,
+        Category::DeliriumOrb,
+        Category::Omen,
+        Category::UniqueRelic,
+        Category::ClusterJewel,
+        Category::BlightedMap,
+        Category::BlightRavagedMap,
+        Category::Invitation,
+        Category::Memory,
+        Category::Coffin,
+        Category::AllflameEmber,
+        Category::Tattoo,
+        Category::Artifact,
+    ];
+
+    // The type name used by poe.ninja and in the Redis keys
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Currency => "Currency",
+            Category::Fragment => "Fragment",
+            Category::Oil => "Oil",
+            Category::Incubator => "Incubator",
+            Category::Scarab => "Scarab",
+            Category::Fossil => "Fossil",
+            Category::Resonator => "Resonator",
+            Category::Essence => "Essence",
+            Category::DivinationCard => "DivinationCard",
+            Category::SkillGem => "SkillGem",
+            Category::BaseType => "BaseType",
+            Category::HelmetEnchant => "HelmetEnchant",
+            Category::UniqueMap => "UniqueMap",
+            Category::Map => "Map",
+            Category::UniqueJewel => "UniqueJewel",
+            Category::UniqueFlask => "UniqueFlask",
+            Category::UniqueWeapon => "UniqueWeapon",
+            Category::UniqueArmour => "UniqueArmour",
+            Category::UniqueAccessory => "UniqueAccessory",
+            Category::Beast => "Beast",
+            Category::Vial => "Vial",
+            Category::DeliriumOrb => "DeliriumOrb",
+            Category::Omen => "Omen",
+            Category::UniqueRelic => "UniqueRelic",
+            Category::ClusterJewel => "ClusterJewel",
+            Category::BlightedMap => "BlightedMap",
+            Category::BlightRavagedMap => "BlightRavagedMap",
+            Category::Invitation => "Invitation",
+            Category::Memory => "Memory",
+            Category::Coffin => "Coffin",
+            Category::AllflameEmber => "AllflameEmber",
+            Category::Tattoo => "Tattoo",
+            Category::Artifact => "Artifact",
+        }
+    }
+
+    pub fn main_category(&self) -> MainCategory {
+        match self {
+            Category::Currency | Category::Fragment => MainCategory::Currency,
+            _ => MainCategory::Item,
+        }
+    }
+
+    // All valid type names, used in error responses
+    pub fn names() -> Vec<&'static str> {
+        Category::ALL.iter().map(|c| c.as_str()).collect()
+    }
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Category, String> {
+        Category::ALL
+            .iter()
+            .find(|c| c.as_str() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown category: {}", s))
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_category_round_trip() {
+        for category in Category::ALL.iter() {
+            assert_eq!(category.as_str().parse::<Category>(), Ok(*category));
+        }
+        assert!("Essense".parse::<Category>().is_err());
+        assert_eq!(Category::Fragment.main_category(), MainCategory::Currency);
+        assert_eq!(Category::DivinationCard.main_category().endpoint(), "itemoverview");
+        assert_eq!("Item".parse::<MainCategory>(), Ok(MainCategory::Item));
+        assert!("Items".parse::<MainCategory>().is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
+use regex::Regex;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+use crate::enums::{Category, MainCategory};
+use crate::models::{AddFilterRequest, FilterKind, FilterList, ReplaceFilterRequest, SkipList};
+use crate::ninja_handler::{bad_request, get_default_filter_key, get_default_skip_key, get_filter_kind_key, get_skip_key, internal_error, parse_category};
+use crate::store::Store;
+use crate::AppState;
+
+// Structs
+#[derive(Deserialize, Debug)]
+pub struct FilterNameParams {
+    name: String,
+}
+
+// Public functions
+// List the filters of every category that tracks a name
+pub async fn list_filters(State(state): State<AppState>) -> Result<(StatusCode, Json<Vec<FilterList>>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let mut filters = Vec::new();
+    for category in Category::ALL {
+        let filter = get_filter_list(category, store).await.map_err(internal_error)?;
+        if !filter.defaults.is_empty() || !filter.names.is_empty() || !filter.globs.is_empty() || !filter.regexes.is_empty() {
+            filters.push(filter);
+        }
+    }
+    Ok((StatusCode::OK, Json(filters)))
+}
+
+// Get the filters of a category
+pub async fn get_filters(State(state): State<AppState>, Path(category): Path<String>) -> Result<(StatusCode, Json<FilterList>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&category)?;
+    let store = state.store.as_ref();
+    let filter = get_filter_list(category, store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(filter)))
+}
+
+// Add a name or a pattern to the user filters of a category, filter_type is the category
+pub async fn add_filter(State(state): State<AppState>, Json(payload): Json<AddFilterRequest>) -> Result<(StatusCode, Json<FilterList>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&payload.filter_type)?;
+    let name = validate_names(&[payload.name], payload.kind)?.remove(0);
+    let store = state.store.as_ref();
+    let filter_key = get_filter_kind_key(category, payload.kind);
+    let status = if store.exist_in_set(&filter_key, &name).await.map_err(internal_error)? {
+        tracing::debug!("Filter {} {} already exists", filter_key, name);
+        StatusCode::OK
+    } else {
+        store.push_set(&filter_key, &name).await.map_err(internal_error)?;
+        tracing::info!("Filter {} {} added", filter_key, name);
+        StatusCode::CREATED
+    };
+    let filter = get_filter_list(category, store).await.map_err(internal_error)?;
+    Ok((status, Json(filter)))
+}
+
+// Replace the user filters of a category, the defaults are left as they are
+pub async fn replace_filters(
+    State(state): State<AppState>,
+    Path(category): Path<String>,
+    Json(payload): Json<ReplaceFilterRequest>,
+) -> Result<(StatusCode, Json<FilterList>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&category)?;
+    let mut replaced = vec![(FilterKind::Exact, validate_names(&payload.names, FilterKind::Exact)?)];
+    if let Some(globs) = &payload.globs {
+        replaced.push((FilterKind::Glob, validate_names(globs, FilterKind::Glob)?));
+    }
+    if let Some(regexes) = &payload.regexes {
+        replaced.push((FilterKind::Regex, validate_names(regexes, FilterKind::Regex)?));
+    }
+    let store = state.store.as_ref();
+    for (kind, names) in replaced {
+        let filter_key = get_filter_kind_key(category, kind);
+        store.replace_set(&filter_key, &names).await.map_err(internal_error)?;
+        tracing::info!("Filter {} replaced with {} entries", filter_key, names.len());
+    }
+    let filter = get_filter_list(category, store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(filter)))
+}
+
+// Remove a name or a pattern from the user filters of a category
+pub async fn delete_filter(
+    State(state): State<AppState>,
+    Path((category, name)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<FilterList>), (StatusCode, Json<Value>)> {
+    remove_filter(&category, name, state.store.as_ref()).await
+}
+
+// Same as delete_filter with the name in the query string, for patterns holding a /
+pub async fn delete_filter_by_query(
+    State(state): State<AppState>,
+    Path(category): Path<String>,
+    query_params: Query<FilterNameParams>,
+) -> Result<(StatusCode, Json<FilterList>), (StatusCode, Json<Value>)> {
+    remove_filter(&category, query_params.0.name, state.store.as_ref()).await
+}
+
+// List the skip lists of every main category
+pub async fn list_skip_lists(State(state): State<AppState>) -> Result<(StatusCode, Json<Vec<SkipList>>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let mut skip_lists = Vec::new();
+    for main_category in MainCategory::ALL {
+        skip_lists.push(get_skip_list(main_category, store).await.map_err(internal_error)?);
+    }
+    Ok((StatusCode::OK, Json(skip_lists)))
+}
+
+// Get the skip list of a main category
+pub async fn get_skip_list_data(
+    State(state): State<AppState>,
+    Path(main_category): Path<String>,
+) -> Result<(StatusCode, Json<SkipList>), (StatusCode, Json<Value>)> {
+    let main_category = parse_main_category(&main_category)?;
+    let store = state.store.as_ref();
+    let skip_list = get_skip_list(main_category, store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(skip_list)))
+}
+
+// Add a skip check, filter_type is the main category and name the category to track entirely
+pub async fn add_skip_check(State(state): State<AppState>, Json(payload): Json<AddFilterRequest>) -> Result<(StatusCode, Json<SkipList>), (StatusCode, Json<Value>)> {
+    if payload.kind != FilterKind::Exact {
+        return Err(bad_request(String::from("skip lists only take category names")));
+    }
+    let main_category = parse_main_category(&payload.filter_type)?;
+    let category = parse_skip_category(&payload.name, main_category)?;
+    let store = state.store.as_ref();
+    let skip_key = get_skip_key(main_category);
+    let status = if store.exist_in_set(&skip_key, category.as_str()).await.map_err(internal_error)? {
+        tracing::debug!("Skip check {} {} already exists", skip_key, category);
+        StatusCode::OK
+    } else {
+        store.push_set(&skip_key, category.as_str()).await.map_err(internal_error)?;
+        tracing::info!("Skip check {} {} added", skip_key, category);
+        StatusCode::CREATED
+    };
+    let skip_list = get_skip_list(main_category, store).await.map_err(internal_error)?;
+    Ok((status, Json(skip_list)))
+}
+
+// Replace the user skip list of a main category, the defaults are left as they are
+pub async fn replace_skip_list(
+    State(state): State<AppState>,
+    Path(main_category): Path<String>,
+    Json(payload): Json<ReplaceFilterRequest>,
+) -> Result<(StatusCode, Json<SkipList>), (StatusCode, Json<Value>)> {
+    let main_category = parse_main_category(&main_category)?;
+    if payload.globs.is_some() || payload.regexes.is_some() {
+        return Err(bad_request(String::from("skip lists only take category names")));
+    }
+    let mut categories = Vec::new();
+    for name in payload.names.iter() {
+        categories.push(parse_skip_category(name, main_category)?.to_string());
+    }
+    let store = state.store.as_ref();
+    let skip_key = get_skip_key(main_category);
+    store.replace_set(&skip_key, &categories).await.map_err(internal_error)?;
+    tracing::info!("Skip check {} replaced with {} categories", skip_key, categories.len());
+    let skip_list = get_skip_list(main_category, store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(skip_list)))
+}
+
+// Remove a category from the user skip list of a main category
+pub async fn delete_skip_check(
+    State(state): State<AppState>,
+    Path((main_category, category)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<SkipList>), (StatusCode, Json<Value>)> {
+    let main_category = parse_main_category(&main_category)?;
+    let store = state.store.as_ref();
+    let skip_list = get_skip_list(main_category, store).await.map_err(internal_error)?;
+    if !skip_list.categories.contains(&category) {
+        return Err(missing_name(&category, &skip_list.defaults, main_category.as_str()));
+    }
+    let skip_key = get_skip_key(main_category);
+    store.remove_set(&skip_key, &category).await.map_err(internal_error)?;
+    tracing::info!("Skip check {} {} deleted", skip_key, category);
+    let skip_list = get_skip_list(main_category, store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(skip_list)))
+}
+
+// Private functions
+// Drop the name from every user filter kind holding it
+async fn remove_filter(category: &str, name: String, store: &dyn Store) -> Result<(StatusCode, Json<FilterList>), (StatusCode, Json<Value>)> {
+    let category = parse_category(category)?;
+    let filter = get_filter_list(category, store).await.map_err(internal_error)?;
+    let kinds: Vec<FilterKind> = [
+        (FilterKind::Exact, &filter.names),
+        (FilterKind::Glob, &filter.globs),
+        (FilterKind::Regex, &filter.regexes),
+    ]
+    .into_iter()
+    .filter(|(_, names)| names.contains(&name))
+    .map(|(kind, _)| kind)
+    .collect();
+    if kinds.is_empty() {
+        return Err(missing_name(&name, &filter.defaults, category.as_str()));
+    }
+    for kind in kinds {
+        let filter_key = get_filter_kind_key(category, kind);
+        store.remove_set(&filter_key, &name).await.map_err(internal_error)?;
+        tracing::info!("Filter {} {} deleted", filter_key, name);
+    }
+    let filter = get_filter_list(category, store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(filter)))
+}
+
+async fn get_filter_list(category: Category, store: &dyn Store) -> redis::RedisResult<FilterList> {
+    Ok(FilterList {
+        category: category.to_string(),
+        defaults: sorted(store.get_set(&get_default_filter_key(category)).await?),
+        names: sorted(store.get_set(&get_filter_kind_key(category, FilterKind::Exact)).await?),
+        globs: sorted(store.get_set(&get_filter_kind_key(category, FilterKind::Glob)).await?),
+        regexes: sorted(store.get_set(&get_filter_kind_key(category, FilterKind::Regex)).await?),
+    })
+}
+
+async fn get_skip_list(main_category: MainCategory, store: &dyn Store) -> redis::RedisResult<SkipList> {
+    Ok(SkipList {
+        main_category: main_category.to_string(),
+        defaults: sorted(store.get_set(&get_default_skip_key(main_category)).await?),
+        categories: sorted(store.get_set(&get_skip_key(main_category)).await?),
+    })
+}
+
+fn sorted(set: HashSet<String>) -> Vec<String> {
+    let mut list: Vec<String> = set.into_iter().collect();
+    list.sort();
+    list
+}
+
+// Trim the names, reject the empty ones and the regexes that do not compile
+fn validate_names(names: &[String], kind: FilterKind) -> Result<Vec<String>, (StatusCode, Json<Value>)> {
+    let names: Vec<String> = names.iter().map(|name| name.trim().to_string()).collect();
+    if names.iter().any(|name| name.is_empty()) {
+        return Err(bad_request(String::from("name must not be empty")));
+    }
+    if kind == FilterKind::Regex {
+        for name in names.iter() {
+            if let Err(e) = Regex::new(name) {
+                return Err(bad_request(format!("Invalid regex {}: {}", name, e)));
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn parse_main_category(main_category: &str) -> Result<MainCategory, (StatusCode, Json<Value>)> {
+    main_category.parse::<MainCategory>().map_err(|e| {
+        tracing::debug!("Reject request: {}", e);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": e,
+                "valid_values": MainCategory::names(),
+            })),
+        )
+    })
+}
+
+// Parse a category of a skip list, it has to belong to the main category
+fn parse_skip_category(name: &str, main_category: MainCategory) -> Result<Category, (StatusCode, Json<Value>)> {
+    let category = parse_category(name)?;
+    if category.main_category() != main_category {
+        let valid_values: Vec<&str> = Category::ALL
+            .iter()
+            .filter(|c| c.main_category() == main_category)
+            .map(|c| c.as_str())
+            .collect();
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": format!("{} belongs to {}, not {}", category, category.main_category(), main_category),
+                "valid_values": valid_values,
+            })),
+        ));
+    }
+    Ok(category)
+}
+
+// A default can only be removed from default.toml, anything else is unknown
+fn missing_name(name: &str, defaults: &[String], owner: &str) -> (StatusCode, Json<Value>) {
+    if defaults.iter().any(|default| default == name) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": format!("{} is a default of {}, remove it from default.toml", name, owner) })),
+        );
+    }
+    (StatusCode::NOT_FOUND, Json(json!({ "error": format!("{} not found in {}", name, owner) })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[tokio::test]
+    async fn test_get_filter_list() {
+        let store = MemoryStore::new("local");
+        store.push_set("Item:Scarab:filter:default", "Titanic Scarab").await.unwrap();
+        store.replace_set("Item:Scarab:filter", &[String::from("Winged Scarab"), String::from("Gilded Scarab")]).await.unwrap();
+        store.push_set("Item:Scarab:filter:glob", "* Scarab of *").await.unwrap();
+        let filter = get_filter_list(Category::Scarab, &store).await.unwrap();
+        assert_eq!(
+            filter,
+            FilterList {
+                category: String::from("Scarab"),
+                defaults: vec![String::from("Titanic Scarab")],
+                names: vec![String::from("Gilded Scarab"), String::from("Winged Scarab")],
+                globs: vec![String::from("* Scarab of *")],
+                regexes: Vec::new(),
+            }
+        );
+        assert_eq!(missing_name("Titanic Scarab", &filter.defaults, "Scarab").0, StatusCode::CONFLICT);
+        assert_eq!(missing_name("Rusted Scarab", &filter.defaults, "Scarab").0, StatusCode::NOT_FOUND);
+        assert!(validate_names(&[String::from(" ")], FilterKind::Exact).is_err());
+        assert!(validate_names(&[String::from("(")], FilterKind::Exact).is_ok());
+        assert!(validate_names(&[String::from("(")], FilterKind::Regex).is_err());
+        assert_eq!(validate_names(&[String::from(" ^Deafening ")], FilterKind::Regex).unwrap(), vec![String::from("^Deafening")]);
+        assert!(parse_skip_category("Scarab", MainCategory::Item).is_ok());
+        assert!(parse_skip_category("Fragment", MainCategory::Item).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_filter() {
+        let store = MemoryStore::new("local");
+        store.push_set("Item:Scarab:filter:regex", "^Winged|Gilded/Polished").await.unwrap();
+        store.push_set("Item:Scarab:filter", "Gilded Scarab").await.unwrap();
+        let (_, Json(filter)) = remove_filter("Scarab", String::from("^Winged|Gilded/Polished"), &store).await.unwrap();
+        assert!(filter.regexes.is_empty());
+        assert_eq!(filter.names, vec![String::from("Gilded Scarab")]);
+        let (status, _) = remove_filter("Scarab", String::from("^Winged|Gilded/Polished"), &store).await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,9 +1,9 @@
-use axum::extract::Query;
 use serde_derive::{Deserialize,Serialize};
 
-use crate::get_profile;
-use crate::redis::RedisInstance;
-use crate::ninja_handler::{request_data_from_ninja,QueryParams,get_data_from_ninja};
+use crate::enums::{Category, MainCategory};
+use crate::store::{SharedStore, Store};
+use crate::models::Threshold;
+use crate::ninja_handler::{request_data_from_ninja,refresh_category,get_threshold_key,get_default_threshold_key,get_default_filter_key,get_default_skip_key};
 #[derive(Deserialize, Debug)]
 struct InitData {
     league: String,
@@ -14,11 +14,21 @@ struct InitData {
 struct DefaultData {
     name: String,
     default: Vec<String>,
+    #[serde(default)]
+    threshold: Option<Threshold>,
 }
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     pub ninja: Ninja,
     pub redis: RedisConfig,
+    #[serde(default)]
+    pub source: SourceConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub job: JobConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
 }
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Ninja {
@@ -27,13 +37,86 @@ pub struct Ninja {
 }
 // Define a struct to hold our Redis configuration values
 #[derive(Deserialize, Serialize, Debug)]
+// username is the ACL user, tls connects with rediss://, ca_cert is a PEM file to trust instead of the system roots
 pub struct RedisConfig {
     pub host: String,
     pub port: u16,
     pub db: u8,
     pub password: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
 }
 
+// Define where the price data is fetched from
+// kind: "ninja" for the live api, "file" for recorded responses under path
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SourceConfig {
+    pub kind: String,
+    pub path: String,
+}
+
+impl Default for SourceConfig {
+    fn default() -> SourceConfig {
+        SourceConfig {
+            kind: String::from("ninja"),
+            path: String::from("fixtures"),
+        }
+    }
+}
+
+// Define how long the price history is kept
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HistoryConfig {
+    pub retention_days: i64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig { retention_days: 30 }
+    }
+}
+
+// Define where the job registry is mirrored on disk and how many categories a job refreshes at once
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct JobConfig {
+    pub file: String,
+    pub max_concurrency: usize,
+}
+
+impl Default for JobConfig {
+    fn default() -> JobConfig {
+        JobConfig {
+            file: String::from("jobs.json"),
+            max_concurrency: 4,
+        }
+    }
+}
+
+// Define where the filters, data and jobs are kept
+// kind: "redis" for the redis section, "memory" for a process local store lost on restart,
+// "sqlite" for the database file at path, "redis+sqlite" for the durable keys in it and the rest in redis
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub kind: String,
+    pub path: String,
+}
+
+impl Default for StoreConfig {
+    fn default() -> StoreConfig {
+        StoreConfig {
+            kind: String::from("redis"),
+            path: String::from("notify.db"),
+        }
+    }
+}
 
 impl Config {
     pub fn to_json_string(&self) -> String {
@@ -42,110 +125,308 @@ impl Config {
     }
 }
 
-pub async fn init_config(profile: &str, redis: &RedisInstance) {
-    // Get config file
+// Read the config file of the profile, the sections needed before the store exists come from here
+pub fn read_config_file(profile: &str) -> Config {
     let config_name = format!("Config_{}.toml", profile);
-    let config_value: String =
-        std::fs::read_to_string(&config_name).expect("Unable to read config file");
-    let config: Config = toml::from_str(&config_value).unwrap();
-    let mut redis = redis.clone();
-    let _ = redis.set(&format!("Config"), &config.to_json_string());
+    let config_value: String = std::fs::read_to_string(&config_name)
+        .unwrap_or_else(|e| panic!("Unable to read config file {}: {}", config_name, e));
+    toml::from_str(&config_value).unwrap_or_else(|e| panic!("Invalid config file {}: {}", config_name, e))
+}
+
+pub async fn init_config(profile: &str, store: &dyn Store) {
+    let config = read_config_file(profile);
+    let _ = store.set(&format!("Config"), &config.to_json_string()).await;
     tracing::info!("Init config done");
 }
 
-pub async fn get_config(profile: &str) -> Config {
-    let mut redis = RedisInstance::new(&profile);
-    if redis.exists(&format!("Config")).unwrap() == false {
-        init_config(profile, &redis).await;
+pub async fn get_config(store: &dyn Store) -> Config {
+    if store.exists(&format!("Config")).await.unwrap() == false {
+        init_config(store.profile(), store).await;
     }
-    let config_string = redis.get(&format!("Config")).unwrap();
+    let config_string = store.get(&format!("Config")).await.unwrap();
     let config: Config = serde_json::from_str(&config_string).unwrap();
     config
 }
 
-pub fn get_redis_config(profile: &str) -> RedisConfig {
-    // Get config file
-    let config_name = format!("Config_{}.toml", profile);
-    let config_value: String =
-        std::fs::read_to_string(&config_name).expect("Unable to read config file");
-    let config: Config = toml::from_str(&config_value).unwrap();
-    config.redis
+// Convert the filter and skip lists written by older versions to sets
+// and drop the one hour expiry they were given, the user filters are kept for good
+// Those lists mixed the defaults in, so the entries of default.toml are taken out of them
+pub async fn migrate_filter_lists(store: &dyn Store) {
+    let init_data = std::fs::read_to_string("default.toml").expect("Unable to read default file");
+    let init_data: InitData = toml::from_str(&init_data).unwrap();
+    for pattern in ["*:*:filter", "*:skip"] {
+        if let Some(cache) = store.cache() {
+            move_from_cache(pattern, cache, store, &init_data).await;
+        }
+        let keys = match store.get_all_keys(pattern).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                tracing::error!("Get {} keys error: {}", pattern, e);
+                continue;
+            }
+        };
+        for key in keys {
+            match store.convert_list_to_set(&key).await {
+                Ok(true) => {
+                    tracing::info!("{} converted to a set", key);
+                    for default_value in get_default_entries(&key, &init_data) {
+                        if let Err(e) = store.remove_set(&key, &default_value).await {
+                            tracing::error!("Remove default {} from {} error: {}", default_value, key, e);
+                        }
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => tracing::error!("Convert {} error: {}", key, e),
+            }
+            if let Err(e) = store.persist(&key).await {
+                tracing::error!("Persist {} error: {}", key, e);
+            }
+        }
+    }
 }
 
-pub async fn init_data(redis: &RedisInstance) {
+// A redis+sqlite store hides the filter and skip lists a redis store left in the cache,
+// merge them into the durable store without the default.toml entries and drop them from the cache
+async fn move_from_cache(pattern: &str, cache: &dyn Store, store: &dyn Store, init_data: &InitData) {
+    let keys = match cache.get_all_keys(pattern).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            tracing::error!("Get {} keys from the cache error: {}", pattern, e);
+            return;
+        }
+    };
+    for key in keys {
+        // lists from older versions, sets from a redis store already migrated
+        let members = match cache.get_list(&key).await {
+            Ok(members) => members,
+            Err(_) => match cache.get_set(&key).await {
+                Ok(members) => members.into_iter().collect(),
+                Err(e) => {
+                    tracing::error!("Read {} from the cache error: {}", key, e);
+                    continue;
+                }
+            },
+        };
+        let defaults = get_default_entries(&key, init_data);
+        let mut moved = true;
+        for member in members.iter().filter(|member| !defaults.contains(member)) {
+            if let Err(e) = store.push_set(&key, member).await {
+                tracing::error!("Move {} {} to the durable store error: {}", key, member, e);
+                moved = false;
+            }
+        }
+        if !moved {
+            continue;
+        }
+        match cache.delete(&key).await {
+            Ok(_) => tracing::info!("{} moved from the cache to the durable store", key),
+            Err(e) => tracing::error!("Delete {} from the cache error: {}", key, e),
+        }
+    }
+}
+
+pub async fn init_data(store: &dyn Store) {
     // Get initial data from default.toml
-    let mut redis = redis.clone();
     let init_data = std::fs::read_to_string("default.toml").expect("Unable to read default file");
     let init_data: InitData = toml::from_str(&init_data).unwrap();
 
     tracing::info!("InitData: {:?}", init_data);
     // Put initial data into redis for default filter
     for currency in init_data.currency {
-        // delete filter data first
-        let filter_key = format!("Currency:{}:filter", currency.name);
-        let _ = redis.delete(&filter_key);
-        for default_value in currency.default {
-            let _ = redis.push_list(&filter_key, &default_value);
+        let category = match get_default_category(&currency, MainCategory::Currency) {
+            Some(category) => category,
+            None => continue,
+        };
+        init_threshold(category, &currency, store).await;
+        // rewrite the defaults at once, the user filters are left as they are
+        let filter_key = get_default_filter_key(category);
+        if let Err(e) = store.replace_set(&filter_key, &currency.default).await {
+            tracing::error!("Write {} error: {}", filter_key, e);
         }
-        // set list expire time
-        let _ = redis.set_expire(&filter_key, 3600);
     }
     let mut item_name_list = Vec::new();
     for item in init_data.item {
-        // delete filter data first
-        let filter_key = format!("Item:{}:filter", item.name);
-        let _ = redis.delete(&filter_key);
-        for default_value in item.default {
-            let _ = redis.push_list(&filter_key, &default_value);
-        }
-        // set list expire time
-        let _ = redis.set_expire(&filter_key, 3600);
+        let category = match get_default_category(&item, MainCategory::Item) {
+            Some(category) => category,
+            None => continue,
+        };
+        init_threshold(category, &item, store).await;
+        // rewrite the defaults at once, the user filters are left as they are
+        let filter_key = get_default_filter_key(category);
+        if let Err(e) = store.replace_set(&filter_key, &item.default).await {
+            tracing::error!("Write {} error: {}", filter_key, e);
+        }
         item_name_list.push(item.name);
     }
-    // set skip list
-    let skip_key = format!("Item:skip");
-    let _ = redis.delete(&skip_key);
-    let _ = redis.set_list_expire(&skip_key, &item_name_list, 3600);
+    // set default skip list
+    let skip_key = get_default_skip_key(MainCategory::Item);
+    if let Err(e) = store.replace_set(&skip_key, &item_name_list).await {
+        tracing::error!("Write {} error: {}", skip_key, e);
+    }
     // set divine to chaos ratio
     let league = init_data.league;
-    let category = String::from("Currency");
-    let api_response = request_data_from_ninja(&league, &category).await;
+    let api_response = request_data_from_ninja(&league, Category::Currency, store).await;
     let api_response = api_response.currency_response.unwrap();
     let api_response = api_response.lines;
     for item in api_response {
         if item.currencyTypeName == "Divine Orb" {
-            let _ = redis.push_hash_expire(&format!("{}:D2C",&league),"ratio", &item.chaosEquivalent.to_string(), 3600);
-            let _ = redis.push_hash_expire(&format!("{}:D2C",&league),"update_time", &chrono::offset::Utc::now().to_string(), 3600);
+            let _ = store.push_hash_expire(&format!("{}:D2C",&league),"ratio", &item.chaosEquivalent.to_string(), 3600).await;
+            let _ = store.push_hash_expire(&format!("{}:D2C",&league),"update_time", &chrono::offset::Utc::now().to_string(), 3600).await;
         }
     }
     tracing::info!("Init data done");
 }
 
-pub async fn init_call_before_start() {
+// Entries default.toml puts in a filter or skip key, the skip list of Item holds every item category
+fn get_default_entries(key: &str, init_data: &InitData) -> Vec<String> {
+    let parts: Vec<&str> = key.split(':').collect();
+    match parts.as_slice() {
+        ["Item", "skip"] => init_data.item.iter().map(|data| data.name.clone()).collect(),
+        [main_category, category, "filter"] => {
+            let section = match *main_category {
+                "Currency" => &init_data.currency,
+                "Item" => &init_data.item,
+                _ => return Vec::new(),
+            };
+            section.iter().filter(|data| data.name == *category).flat_map(|data| data.default.clone()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Get the category of a default.toml entry if it is known and listed in the right section
+fn get_default_category(data: &DefaultData, main_category: MainCategory) -> Option<Category> {
+    match data.name.parse::<Category>() {
+        Ok(category) if category.main_category() == main_category => Some(category),
+        Ok(category) => {
+            tracing::error!("{} is not a {} category, skip it", category, main_category);
+            None
+        }
+        Err(e) => {
+            tracing::error!("{}, valid values: {:?}", e, Category::names());
+            None
+        }
+    }
+}
+
+// Set the default threshold of a category from default.toml, remove it if not configured
+// The threshold set through the API is left as it is, unless it is the default older versions wrote there
+async fn init_threshold(category: Category, data: &DefaultData, store: &dyn Store) {
+    let default_key = get_default_threshold_key(category);
+    match &data.threshold {
+        Some(threshold) => {
+            let threshold = threshold.to_json_string();
+            let _ = store.set(&default_key, &threshold).await;
+            let threshold_key = get_threshold_key(category);
+            if store.get(&threshold_key).await.is_ok_and(|current| current == threshold) {
+                let _ = store.delete(&threshold_key).await;
+            }
+        }
+        None => {
+            let _ = store.delete(&default_key).await;
+        }
+    }
+}
+
+pub async fn init_call_before_start(store: &SharedStore) {
     // Call get ninja data before start the server
     let init_data = std::fs::read_to_string("default.toml").expect("Unable to read default file");
     let init_data: InitData = toml::from_str(&init_data).unwrap();
     let league = init_data.league;
-    for currency in init_data.currency {
-        let query_params = QueryParams::new(league.clone(), currency.name);
-        tracing::debug!("Init call with query params: {:?}", query_params);
-        let _ = tokio::spawn(async {
-            let _ = get_data_from_ninja(Query(query_params)).await;
-        });
-    }
-    for item in init_data.item {
-        let query_params = QueryParams::new(league.clone(), item.name);
-        tracing::debug!("Init call with query params: {:?}", query_params);
-        let _ = tokio::spawn(async {
-            let _ = get_data_from_ninja(Query(query_params)).await;
+    for data in init_data.currency.into_iter().chain(init_data.item) {
+        let category = match data.name.parse::<Category>() {
+            Ok(category) => category,
+            Err(e) => {
+                tracing::error!("Skip init call: {}", e);
+                continue;
+            }
+        };
+        tracing::debug!("Init call with {} {}", league, category);
+        let league = league.clone();
+        let store = store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = refresh_category(&league, category, store.as_ref()).await {
+                tracing::error!("Init call {} error: {}", category, e);
+            }
         });
     }
     tracing::info!("Call get ninja data before start the server done");
 }
 
+// Memory store whose config reads the recorded responses under fixtures, for offline tests
+#[cfg(test)]
+pub(crate) async fn get_offline_store() -> crate::store::MemoryStore {
+    let store = crate::store::MemoryStore::new("local");
+    let config = Config {
+        ninja: Ninja { currency: String::new(), item: String::new() },
+        redis: RedisConfig {
+            host: String::from("127.0.0.1"),
+            port: 6379,
+            db: 0,
+            password: None,
+            username: None,
+            tls: false,
+            ca_cert: None,
+            connect_timeout_ms: None,
+        },
+        source: SourceConfig { kind: String::from("file"), path: String::from("fixtures") },
+        history: HistoryConfig::default(),
+        job: JobConfig::default(),
+        store: StoreConfig { kind: String::from("memory"), ..StoreConfig::default() },
+    };
+    store.set("Config", &config.to_json_string()).await.unwrap();
+    store
+}
 #[cfg(test)]
 #[tokio::test]
 async fn test_get_init_data() {
-    let redis = RedisInstance::new("local");
-    init_data(&redis).await;
-}
\ No newline at end of file
+    let store = get_offline_store().await;
+    init_data(&store).await;
+    assert!(store.exist_in_set("Currency:Currency:filter:default", "Divine Orb").await.unwrap());
+    assert!(store.exist_in_set("Item:skip:default", "Essence").await.unwrap());
+    assert!(store.get_hash("Affliction:D2C", "ratio").await.unwrap().parse::<f64>().unwrap() > 0.0);
+}
+#[cfg(test)]
+#[tokio::test]
+async fn test_migrate_filter_lists_from_cache() {
+    use crate::store::{MemoryStore, SplitStore};
+    use std::sync::Arc;
+    let cache = Arc::new(MemoryStore::new("local"));
+    let durable = Arc::new(MemoryStore::new("local"));
+    let filter = vec![String::from("Divine Orb"), String::from("Mirror of Kalandra")];
+    cache.replace_list_expire("Currency:Currency:filter", &filter, 3600).await.unwrap();
+    cache.push_set("Item:skip", "Essence").await.unwrap();
+    cache.push_set("Item:skip", "Scarab").await.unwrap();
+    let store = SplitStore::new(cache.clone(), durable.clone());
+    migrate_filter_lists(&store).await;
+    assert!(!cache.exists("Currency:Currency:filter").await.unwrap());
+    assert!(!cache.exists("Item:skip").await.unwrap());
+    let filter = durable.get_set("Currency:Currency:filter").await.unwrap();
+    assert_eq!(filter.into_iter().collect::<Vec<String>>(), vec![String::from("Mirror of Kalandra")]);
+    let skip = durable.get_set("Item:skip").await.unwrap();
+    assert_eq!(skip.into_iter().collect::<Vec<String>>(), vec![String::from("Scarab")]);
+}
+#[cfg(test)]
+#[test]
+fn test_get_default_entries() {
+    let init_data: InitData = toml::from_str(
+        r#"
+        league = "Affliction"
+        [[currency]]
+        name = "Currency"
+        default = ["Divine Orb"]
+        [[item]]
+        name = "Scarab"
+        default = ["Titanic Scarab"]
+        [[item]]
+        name = "Essence"
+        default = []
+        "#,
+    )
+    .unwrap();
+    assert_eq!(get_default_entries("Currency:Currency:filter", &init_data), vec![String::from("Divine Orb")]);
+    assert_eq!(get_default_entries("Item:Scarab:filter", &init_data), vec![String::from("Titanic Scarab")]);
+    assert_eq!(get_default_entries("Item:skip", &init_data), vec![String::from("Scarab"), String::from("Essence")]);
+    assert!(get_default_entries("Currency:skip", &init_data).is_empty());
+    assert!(get_default_entries("Item:Currency:filter", &init_data).is_empty());
+}
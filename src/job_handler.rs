@@ -1,16 +1,53 @@
-use axum::{extract::{Query,State}, response::IntoResponse};
-use tokio_cron_scheduler::Job;
+use axum::{body::Bytes, extract::{Path,Query,State}, http::StatusCode, response::IntoResponse, Json};
+use chrono_tz::Tz;
+use redis::RedisResult;
+use serde_json::{json, Value};
+use tokio_cron_scheduler::{Job, JobScheduler};
 use uuid::Uuid;
 use std::collections::HashMap;
 use serde_derive::Deserialize;
 
-use crate::{get_profile, ninja_handler::{QueryParams,get_data_from_ninja, self}};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::{AbortHandle, JoinSet};
+
+use crate::ninja_handler::{internal_error, parse_category, refresh_category, self};
+use crate::enums::Category;
 use crate::AppState;
+use crate::init::{get_config, read_config_file};
+use crate::models::{ActiveJobRequest, JobRecord, ReportResult, ScheduleConfig};
+use crate::store::{SharedStore, Store};
 use crate::discord::send_message_to_channel;
 
+// Redis hash of the job registry, job id to JobRecord
+const JOB_KEY: &str = "Jobs";
+
+// Redis key of the lease around the runs of a job and its fencing token counter
+fn lease_key(id: &str) -> String {
+    format!("Lease:{}", id)
+}
+
+fn lease_token_key(id: &str) -> String {
+    format!("Lease:{}:token", id)
+}
+
+// Runs in flight by job id and run id, used to cancel them, runs of a job can overlap
+pub type RunningJobs = Arc<Mutex<HashMap<Uuid, HashMap<Uuid, AbortHandle>>>>;
+
+// Held while checking the registry for an equivalent schedule and adding the job
+pub type RegisterLock = Arc<tokio::sync::Mutex<()>>;
+
+#[derive(Deserialize, Debug)]
+pub struct ReportQuery {
+    #[serde(default)]
+    category: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct InitData {
     league: String,
+    #[serde(default)]
+    schedule: Vec<ScheduleConfig>,
 }
 impl InitData {
     pub fn get_league(&self) -> String {
@@ -20,122 +57,638 @@ impl InitData {
 
 // Public functions
 // 排程 啟動!
-pub async fn active_probe_job(State(state): State<AppState>) -> impl IntoResponse{
+// The schedules come from the request body, or default.toml when the body is empty
+// Every schedule is built first, so an invalid one rejects the request without adding any job
+pub async fn active_probe_job(State(state): State<AppState>, body: Bytes) -> impl IntoResponse{
     // using app state
     let scheduler = state.scheduler.clone();
+    let schedules = if body.iter().all(u8::is_ascii_whitespace) {
+        get_init_data().schedule
+    } else {
+        match serde_json::from_slice::<ActiveJobRequest>(&body) {
+            Ok(payload) => payload.schedules,
+            Err(e) => {
+                tracing::debug!("Reject request: {}", e);
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": format!("Invalid body: {}", e) })));
+            }
+        }
+    };
+    let schedules = if schedules.is_empty() { vec![ScheduleConfig::default()] } else { schedules };
 
-
-    // Add job
-    let add = scheduler.add(
-        Job::new("0 * * * * *", |_uuid, _l| {
-            // Get initial data from default.toml
-            let init_data = std::fs::read_to_string("default.toml").expect("Unable to read default file");
-            let init_data: InitData = toml::from_str(&init_data).unwrap();
-            tracing::info!("Job running every hour");
-            // Get the profile
-            let profile = get_profile();
-            // Get the refresh key map
-            let refresh_key_map = get_the_refresh_key_map();
-            tracing::debug!("Refresh key map: {:?}", refresh_key_map);
-            // loop the refresh key map
-            for (main_type, sub_type_list) in refresh_key_map {
-                // loop the sub type list
-                for sub_type in sub_type_list {
-                    // Refresh data
-                    let query_params = QueryParams::new(init_data.get_league(), sub_type.clone());
-                    tracing::debug!("Query params: {:?}", query_params);
-                    let _ = tokio::spawn(async {
-                        let _ = get_data_from_ninja(Query(query_params)).await;
-                    });
-                }
-                // Get the output data from redis
-                let output = ninja_handler::get_format_output(&main_type, &profile);
-                // tracing::debug!("Output: {:?}", output);
-                // Send the output data to discord
-                let _ = tokio::spawn(async {
-                    let _ = send_message_to_channel(output).await;
-                });
+    // Concurrent requests would both miss each other's jobs in the registry
+    let _registering = state.registering.lock().await;
+    let store = state.store.as_ref();
+    let records = match get_job_records(store).await {
+        Ok(records) => records,
+        Err(e) => return internal_error(e),
+    };
+    let mut jobs = Vec::new();
+    let mut existing = Vec::new();
+    let mut pending: Vec<(ScheduleConfig, Job)> = Vec::new();
+    for schedule in schedules {
+        // Reuse the registered job of an equivalent schedule
+        if let Some(record) = records.iter().find(|record| record.schedule.is_equivalent(&schedule)) {
+            tracing::info!("Job {} already scheduled as {}", schedule.name, record.id);
+            existing.push(record.id.clone());
+            jobs.push(record.clone());
+            continue;
+        }
+        if pending.iter().any(|(other, _)| other.is_equivalent(&schedule)) {
+            tracing::info!("Job {} listed twice, add it once", schedule.name);
+            continue;
+        }
+        match build_probe_job(&schedule, None, state.running.clone(), state.store.clone()) {
+            Ok(job) => pending.push((schedule, job)),
+            Err(e) => {
+                tracing::error!("Job {} failed to build: {}", schedule.name, e);
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": e })));
             }
-    
-        }).expect("Failed to create job"),
-    ).await;
-    match add {
-        Ok(_) => {
-            let uuid = add.unwrap();
-            let uuid = uuid.to_string();
-            // Add job to redis
-            let mut redis = state.redis.clone();
-            let _ = redis.set_with_expire("probe_job", &uuid, 3600);
-            println!("Job added with uuid: {}", uuid);
         }
-        Err(e) => {
-            println!("Job failed to add: {}", e);
+    }
+    // The jobs added before a failure stay registered and scheduled
+    let mut failure = None;
+    for (schedule, job) in pending {
+        // Add job
+        let add = scheduler.add(job).await;
+        match add {
+            Ok(uuid) => {
+                // Add job to the registry, a job missing there can not be listed, deduplicated or deleted
+                let record = JobRecord::new(uuid.to_string(), schedule);
+                if let Err(e) = save_job_record(&record, store).await {
+                    tracing::error!("Job {} failed to register: {}", record.schedule.name, e);
+                    if let Err(e) = scheduler.remove(&uuid).await {
+                        tracing::error!("Job {} failed to delete: {}", uuid, e);
+                    }
+                    failure = Some(internal_error(e));
+                    break;
+                }
+                tracing::info!("Job {} added with uuid: {}", record.schedule.name, uuid);
+                jobs.push(record);
+            }
+            Err(e) => {
+                tracing::error!("Job {} failed to add: {}", schedule.name, e);
+                failure = Some((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "error": format!("Job {} failed to add", schedule.name) })),
+                ));
+                break;
+            }
         }
     }
     let scheduler = scheduler.start().await;
     match scheduler {
         Ok(_) => {
-            println!("Scheduler started");
+            tracing::info!("Scheduler started");
         }
         Err(e) => {
-            println!("Scheduler failed to start: {}", e);
+            tracing::error!("Scheduler failed to start: {}", e);
         }
     }
-    "排程 啟動!" 
+    if let Some(failure) = failure {
+        return failure;
+    }
+    (StatusCode::OK, Json(json!({ "message": "排程 啟動!", "jobs": jobs, "existing": existing })))
 }
 
+// Re-register the persisted jobs, used at startup, return the number restored
+pub async fn restore_jobs(state: &AppState) -> usize {
+    let store = state.store.as_ref();
+    let records = match get_job_records(store).await {
+        Ok(records) if !records.is_empty() => records,
+        Ok(_) => read_job_file(store.profile()),
+        Err(e) => {
+            tracing::error!("Get jobs from Redis error: {}, read the job file", e);
+            read_job_file(store.profile())
+        }
+    };
+    let mut restored = 0;
+    for record in records {
+        let uuid = match Uuid::parse_str(&record.id) {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                tracing::error!("Job {} invalid uuid: {}", record.id, e);
+                continue;
+            }
+        };
+        let job = match build_probe_job(&record.schedule, Some(uuid), state.running.clone(), state.store.clone()) {
+            Ok(job) => job,
+            Err(e) => {
+                tracing::error!("Job {} failed to build: {}", record.id, e);
+                continue;
+            }
+        };
+        match state.scheduler.add(job).await {
+            Ok(_) => {
+                if let Err(e) = save_job_record(&record, store).await {
+                    tracing::error!("Job {} failed to register: {}", record.id, e);
+                    if let Err(e) = state.scheduler.remove(&uuid).await {
+                        tracing::error!("Job {} failed to delete: {}", uuid, e);
+                    }
+                    continue;
+                }
+                tracing::info!("Job {} {} restored", record.schedule.name, record.id);
+                restored += 1;
+            }
+            Err(e) => tracing::error!("Job {} failed to restore: {}", record.id, e),
+        }
+    }
+    restored
+}
+
+// Delete every registered job
 pub async fn delete_probe_job(State(state): State<AppState>) -> impl IntoResponse{
     // using app state
     let scheduler = state.scheduler.clone();
     // Delete job
-    let mut redis = state.redis.clone();
-    let uuid = redis.get("probe_job").unwrap();
-    match uuid {
-        _ => {
-            if uuid.is_empty() {
-                return "排程 沒有啟動!";
+    let store = state.store.as_ref();
+    let records = get_job_records(store).await.unwrap_or_default();
+    if records.is_empty() {
+        return "排程 沒有啟動!";
+    }
+    for record in records {
+        remove_job(&scheduler, &record.id, store).await;
+    }
+    "排程 倒了!"
+}
+
+// List the registered jobs
+pub async fn list_jobs(State(state): State<AppState>) -> Result<(StatusCode, Json<Vec<JobRecord>>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let mut records = get_job_records(store).await.map_err(internal_error)?;
+    for record in records.iter_mut() {
+        set_next_run(&state, record).await;
+    }
+    Ok((StatusCode::OK, Json(records)))
+}
+
+// Get a registered job
+pub async fn get_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let mut record = find_job_record(&id, store).await?;
+    set_next_run(&state, &mut record).await;
+    Ok((StatusCode::OK, Json(record)))
+}
+
+// Remove a job from the scheduler and the registry
+pub async fn delete_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let record = find_job_record(&id, store).await?;
+    remove_job(&state.scheduler, &id, store).await;
+    Ok((StatusCode::OK, Json(record)))
+}
+
+// Keep the job scheduled but skip its runs until resumed
+pub async fn pause_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, Json<Value>)> {
+    set_paused(&state, &id, true).await
+}
+
+pub async fn resume_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, Json<Value>)> {
+    set_paused(&state, &id, false).await
+}
+
+// Abort the runs of a job that are in flight, the job stays scheduled
+pub async fn cancel_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<Value>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    find_job_record(&id, store).await?;
+    let runs = Uuid::parse_str(&id)
+        .ok()
+        .and_then(|uuid| state.running.lock().unwrap().remove(&uuid))
+        .filter(|runs| !runs.is_empty());
+    match runs {
+        Some(runs) => {
+            for handle in runs.values() {
+                handle.abort();
             }
-            let uuid = Uuid::parse_str(&uuid).unwrap();
-            let delete = scheduler.remove(&uuid).await;
-            match delete {
-                Ok(_) => {
-                    tracing::info!("Job {} deleted", uuid);
-                    let _ = redis.delete("probe_job");
-                }
-                Err(e) => {
-                    tracing::error!("Job {} failed to delete: {}", uuid, e);
+            tracing::info!("Job {} {} runs cancelled", id, runs.len());
+            Ok((StatusCode::OK, Json(json!({ "message": format!("Job {} run cancelled", id), "runs": runs.len() }))))
+        }
+        None => Err((
+            StatusCode::CONFLICT,
+            Json(json!({ "error": format!("Job {} is not running", id) })),
+        )),
+    }
+}
+
+// Run a job now, also when it is paused, and return the sent reports
+pub async fn run_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<ReportResult>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let record = find_job_record(&id, store).await?;
+    let categories = record
+        .schedule
+        .get_category_map()
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))))?;
+    tracing::info!("Job {} run manually", id);
+    let report = match run_report(categories, &state.store).await {
+        Ok(report) => report,
+        Err(e) => {
+            record_job_outcome(&id, "failed", Some(e.to_string()), None, store).await;
+            return Err(internal_error(e));
+        }
+    };
+    record_report(&id, &report, None, store).await;
+    Ok((StatusCode::OK, Json(report)))
+}
+
+// Run the report of comma separated categories without a job, every tracked category if none is given
+pub async fn run_report_now(State(state): State<AppState>, Query(query): Query<ReportQuery>) -> Result<(StatusCode, Json<ReportResult>), (StatusCode, Json<Value>)> {
+    let mut categories = Vec::new();
+    for category in query.category.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        categories.push(parse_category(category)?.to_string());
+    }
+    let schedule = ScheduleConfig { categories, ..ScheduleConfig::default() };
+    let categories = schedule
+        .get_category_map()
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))))?;
+    let report = run_report(categories, &state.store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(report)))
+}
+
+// Private functions
+async fn set_paused(state: &AppState, id: &str, paused: bool) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let mut record = find_job_record(id, store).await?;
+    record.paused = paused;
+    save_job_record(&record, store).await.map_err(internal_error)?;
+    tracing::info!("Job {} {}", id, if paused { "paused" } else { "resumed" });
+    set_next_run(state, &mut record).await;
+    Ok((StatusCode::OK, Json(record)))
+}
+
+fn get_init_data() -> InitData {
+    // Get initial data from default.toml
+    let init_data = std::fs::read_to_string("default.toml").expect("Unable to read default file");
+    toml::from_str(&init_data).unwrap()
+}
+
+// Build the refresh and report job of a schedule, keeping the id of a restored job
+// Every run is spawned as its own task and kept in running so it can be cancelled
+fn build_probe_job(schedule: &ScheduleConfig, id: Option<Uuid>, running: RunningJobs, store: SharedStore) -> Result<Job, String> {
+    let timezone: Tz = schedule
+        .timezone
+        .parse()
+        .map_err(|e| format!("Invalid timezone {}: {}", schedule.timezone, e))?;
+    let categories = schedule.get_category_map()?;
+    let cron = schedule.cron.clone();
+    let schedule = schedule.clone();
+    let mut job = Job::new_async_tz(cron.as_str(), timezone, move |uuid, _l| {
+        let run = tokio::spawn(run_probe_job(uuid, schedule.clone(), categories.clone(), store.clone()));
+        let run_id = Uuid::new_v4();
+        running.lock().unwrap().entry(uuid).or_default().insert(run_id, run.abort_handle());
+        let running = running.clone();
+        let store = store.clone();
+        Box::pin(async move {
+            let res = run.await;
+            // Only drop this run, an overlapping one keeps its handle
+            {
+                let mut running = running.lock().unwrap();
+                if let Some(runs) = running.get_mut(&uuid) {
+                    runs.remove(&run_id);
+                    if runs.is_empty() {
+                        running.remove(&uuid);
+                    }
                 }
             }
+            if matches!(res, Err(ref e) if e.is_cancelled()) {
+                record_job_outcome(&uuid.to_string(), "cancelled", None, None, store.as_ref()).await;
+            }
+        })
+    })
+    .map_err(|e| format!("Invalid cron {}: {}", cron, e))?;
+    if let Some(id) = id {
+        set_job_id(&mut job, id)?;
+    }
+    Ok(job)
+}
+
+// Give a job a known id, the scheduler generates one otherwise
+pub fn set_job_id(job: &mut Job, id: Uuid) -> Result<(), String> {
+    let mut job_data = job.job_data().map_err(|e| format!("Get job data error: {}", e))?;
+    job_data.id = Some(id.into());
+    job.set_job_data(job_data).map_err(|e| format!("Set job data error: {}", e))
+}
+
+// Refresh the categories of a schedule, send the report and record the outcome
+async fn run_probe_job(uuid: Uuid, schedule: ScheduleConfig, categories: Option<HashMap<String, Vec<String>>>, store: SharedStore) {
+    let id = uuid.to_string();
+    if get_job_record(&id, store.as_ref()).await.is_some_and(|record| record.paused) {
+        tracing::debug!("Job {} paused, skip the run", schedule.name);
+        return;
+    }
+    // Take the lease so another instance on the same Redis skips this tick
+    // It is never released, it expires through PX so a replica ticking a little later still sees it
+    let lease = match schedule.lease_ms {
+        Some(lease_ms) => match acquire_lease(&id, lease_ms, store.as_ref()).await {
+            Ok(Some(lease)) => Some(lease),
+            Ok(None) => {
+                let holder = store.get(&lease_key(&id)).await.unwrap_or_default();
+                tracing::info!("Job {} leased by {}, skip the run", schedule.name, holder);
+                return;
+            }
+            Err(e) => {
+                tracing::error!("Job {} lease error: {}", schedule.name, e);
+                return;
+            }
+        },
+        None => None,
+    };
+    tracing::info!("Job {} running on {}", schedule.name, schedule.cron);
+    let token = lease.as_ref().map(|(_, token)| *token);
+    match run_report(categories, &store).await {
+        Ok(report) => record_report(&id, &report, token, store.as_ref()).await,
+        Err(e) => {
+            tracing::error!("Job {} report error: {}", schedule.name, e);
+            record_job_outcome(&id, "failed", Some(e.to_string()), token, store.as_ref()).await;
+        }
+    }
+    if let Some((value, _)) = lease {
+        if store.get(&lease_key(&id)).await.ok().as_ref() != Some(&value) {
+            tracing::error!("Job {} lease expired before the run finished", schedule.name);
         }
     }
-    "排程 倒了!"    
+}
 
+// Take the lease of a job, return the lease value and its fencing token, None if another instance holds it
+async fn acquire_lease(id: &str, lease_ms: u64, store: &dyn Store) -> RedisResult<Option<(String, i64)>> {
+    let token = store.incr(&lease_token_key(id)).await?;
+    let value = format!("{}:{}", instance_name(), token);
+    if store.set_nx_px(&lease_key(id), &value, lease_ms).await? {
+        Ok(Some((value, token)))
+    } else {
+        Ok(None)
+    }
 }
 
-// Private functions
-fn get_the_refresh_key_map() -> HashMap<String, Vec<String>> {
+// Host and process id of this instance
+fn instance_name() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or(String::from("localhost"));
+    format!("{}-{}", host, std::process::id())
+}
+
+// Refresh the categories, then render and send their reports
+// Fails only when the tracked categories can not be listed from the store
+pub async fn run_report(categories: Option<HashMap<String, Vec<String>>>, store: &SharedStore) -> RedisResult<ReportResult> {
+    let init_data = get_init_data();
+    // Get the refresh key map, every tracked category if none is given
+    let refresh_key_map = match categories {
+        Some(categories) => categories,
+        None => get_the_refresh_key_map(store.as_ref()).await?,
+    };
+    tracing::debug!("Refresh key map: {:?}", refresh_key_map);
+    // Refresh every category before rendering so the report has this tick's data
+    let sub_types = refresh_key_map.values().flatten().cloned().collect();
+    let max_concurrency = get_config(store.as_ref()).await.job.max_concurrency;
+    let failed = refresh_categories(&init_data.get_league(), sub_types, store, max_concurrency).await;
+    let mut report = ReportResult {
+        sent: Vec::new(),
+        errors: failed.iter().map(|(name, e)| format!("{}: {}", name, e)).collect(),
+    };
+    // loop the refresh key map
+    for (main_type, sub_type_list) in refresh_key_map {
+        // Get the output data from redis
+        let mut output = ninja_handler::get_format_output(&main_type, &sub_type_list, store.as_ref()).await;
+        let failed_names: Vec<&str> = failed
+            .iter()
+            .filter(|(name, _)| sub_type_list.contains(name))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !failed_names.is_empty() {
+            output.push_str(&format!("- **Refresh failed**: {}\n", failed_names.join(", ")));
+        }
+        // tracing::debug!("Output: {:?}", output);
+        // Send the output data to discord
+        match send_message_to_channel(output.clone()).await {
+            Ok(_) => report.sent.push(output),
+            Err(e) => report.errors.push(format!("{}: {}", main_type, e)),
+        }
+    }
+    Ok(report)
+}
+
+async fn record_report(id: &str, report: &ReportResult, token: Option<i64>, store: &dyn Store) {
+    if report.errors.is_empty() {
+        record_job_outcome(id, "success", None, token, store).await;
+    } else {
+        record_job_outcome(id, "failed", Some(report.errors.join("; ")), token, store).await;
+    }
+}
+
+// Refresh the categories with at most max_concurrency requests in flight, return the failed ones with the reason
+async fn refresh_categories(league: &str, sub_types: Vec<String>, store: &SharedStore, max_concurrency: usize) -> Vec<(String, String)> {
+    let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    // A task that panics or is aborted only returns its id, keep the category it refreshes
+    let mut task_sub_types = HashMap::new();
+    for sub_type in sub_types {
+        let semaphore = semaphore.clone();
+        let league = league.to_string();
+        let store = store.clone();
+        let category = sub_type.parse::<Category>();
+        let task = tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            match category {
+                Ok(category) => refresh_category(&league, category, store.as_ref()).await.map(|_| ()),
+                Err(e) => Err(e),
+            }
+        });
+        task_sub_types.insert(task.id(), sub_type);
+    }
+    let mut failed = Vec::new();
+    while let Some(res) = tasks.join_next_with_id().await {
+        let (id, res) = match res {
+            Ok((id, res)) => (id, res),
+            Err(e) => (e.id(), Err(format!("Refresh task error: {}", e))),
+        };
+        if let Err(e) = res {
+            let sub_type = task_sub_types.remove(&id).unwrap_or_default();
+            tracing::error!("Refresh {} error: {}", sub_type, e);
+            failed.push((sub_type, e));
+        }
+    }
+    failed
+}
+
+// Store the outcome of a run in the registry, unless a run with a newer fencing token already did
+async fn record_job_outcome(id: &str, outcome: &str, error: Option<String>, token: Option<i64>, store: &dyn Store) {
+    let mut record = match get_job_record(id, store).await {
+        Some(record) => record,
+        None => {
+            tracing::error!("Job {} not in registry", id);
+            return;
+        }
+    };
+    if let (Some(token), Some(last_token)) = (token, record.fencing_token) {
+        if token < last_token {
+            tracing::error!("Job {} outcome of token {} dropped, token {} is newer", id, token, last_token);
+            return;
+        }
+    }
+    if token.is_some() {
+        record.fencing_token = token;
+    }
+    record.last_run_at = Some(chrono::offset::Utc::now().to_string());
+    record.last_outcome = Some(String::from(outcome));
+    record.last_error = error;
+    let _ = save_job_record(&record, store).await;
+}
+
+async fn remove_job(scheduler: &JobScheduler, id: &str, store: &dyn Store) {
+    match Uuid::parse_str(id) {
+        Ok(uuid) => match scheduler.remove(&uuid).await {
+            Ok(_) => tracing::info!("Job {} deleted", uuid),
+            Err(e) => tracing::error!("Job {} failed to delete: {}", uuid, e),
+        },
+        Err(e) => tracing::error!("Job {} invalid uuid: {}", id, e),
+    }
+    let _ = store.remove_hash(JOB_KEY, id).await;
+    write_job_file(store).await;
+}
+
+// Fill the fields read from the scheduler
+async fn set_next_run(state: &AppState, record: &mut JobRecord) {
+    let mut scheduler = state.scheduler.clone();
+    if let Ok(uuid) = Uuid::parse_str(&record.id) {
+        record.running = state.running.lock().unwrap().get(&uuid).is_some_and(|runs| !runs.is_empty());
+        let store = state.store.as_ref();
+        record.lease_holder = store.get(&lease_key(&record.id)).await.ok();
+        // A paused job still ticks but does not run
+        record.next_run_at = None;
+        if !record.paused {
+            if let Ok(Some(next_tick)) = scheduler.next_tick_for_job(uuid).await {
+                record.next_run_at = Some(next_tick.to_string());
+            }
+        }
+    }
+}
+
+async fn save_job_record(record: &JobRecord, store: &dyn Store) -> RedisResult<()> {
+    store.push_hash(JOB_KEY, &record.id, &record.to_json_string()).await?;
+    write_job_file(store).await;
+    Ok(())
+}
+
+// Mirror the registry to the job file so the jobs survive a lost Redis
+async fn write_job_file(store: &dyn Store) {
+    let records = match get_job_records(store).await {
+        Ok(records) => records,
+        Err(e) => {
+            tracing::error!("Get jobs error: {}", e);
+            return;
+        }
+    };
+    let job_file = get_config(store).await.job.file;
+    let res = std::fs::write(&job_file, serde_json::to_string_pretty(&records).unwrap());
+    if let Err(e) = res {
+        tracing::error!("Write job file {} error: {}", job_file, e);
+    }
+}
+
+// Only read at startup, when the store has no job, so the file is read directly
+fn read_job_file(profile: &str) -> Vec<JobRecord> {
+    let job_file = read_config_file(profile).job.file;
+    let content = match std::fs::read_to_string(&job_file) {
+        Ok(content) => content,
+        Err(e) => {
+            tracing::debug!("Read job file {} error: {}", job_file, e);
+            return Vec::new();
+        }
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        tracing::error!("Parse job file {} error: {}", job_file, e);
+        Vec::new()
+    })
+}
+
+async fn get_job_records(store: &dyn Store) -> RedisResult<Vec<JobRecord>> {
+    let mut records = Vec::new();
+    for (id, value) in store.get_all_hash(JOB_KEY).await? {
+        match serde_json::from_str::<JobRecord>(&value) {
+            Ok(record) => records.push(record),
+            Err(e) => tracing::error!("Parse job {} error: {}", id, e),
+        }
+    }
+    Ok(records)
+}
+
+async fn get_job_record(id: &str, store: &dyn Store) -> Option<JobRecord> {
+    let value = store.get_hash(JOB_KEY, id).await.ok()?;
+    serde_json::from_str(&value).ok()
+}
+
+async fn find_job_record(id: &str, store: &dyn Store) -> Result<JobRecord, (StatusCode, Json<Value>)> {
+    get_job_record(id, store).await.ok_or((
+        StatusCode::NOT_FOUND,
+        Json(json!({ "error": format!("Job {} not found", id) })),
+    ))
+}
+
+async fn get_the_refresh_key_map(store: &dyn Store) -> RedisResult<HashMap<String, Vec<String>>> {
     let mut refresh_key_map = HashMap::new();
-    let profile = get_profile();
-    let mut redis_instance = crate::redis::RedisInstance::new(&profile);
-    let main_type_list = redis_instance.get_all_keys_name("Data:*");
-    let main_type_list = main_type_list.unwrap();
-    println!("Main type list: {:?}", main_type_list);
+    let main_type_list = store.get_all_keys_name("Data:*").await?;
+    tracing::debug!("Main type list: {:?}", main_type_list);
     for main_type in main_type_list {
         let sub_type_key = format!("Data:{}:*", &main_type);
-        let sub_type_list = redis_instance.get_all_keys_name(&sub_type_key);
-        let sub_type_list = sub_type_list.unwrap();
+        let sub_type_list = store.get_all_keys_name(&sub_type_key).await?;
         // push into refresh_key_map
         refresh_key_map.insert(main_type, sub_type_list);
     }
-    refresh_key_map
+    Ok(refresh_key_map)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::MemoryStore;
+
+    fn get_test_store() -> SharedStore {
+        Arc::new(MemoryStore::new("local"))
+    }
+
     #[test]
-    fn test_get_the_refresh_key_map() {
-        let refresh_key_map = get_the_refresh_key_map();
-        println!("{:?}", refresh_key_map);
+    fn test_build_probe_job() {
+        let mut schedule = ScheduleConfig {
+            name: String::from("currency"),
+            cron: String::from("0 */10 * * * *"),
+            timezone: String::from("Asia/Taipei"),
+            categories: vec![String::from("Currency"), String::from("Scarab")],
+            lease_ms: Some(60000),
+        };
+        assert!(build_probe_job(&schedule, None, RunningJobs::default(), get_test_store()).is_ok());
+        // A restored job keeps its id
+        let id = Uuid::new_v4();
+        assert_eq!(build_probe_job(&schedule, Some(id), RunningJobs::default(), get_test_store()).unwrap().guid(), id);
+        schedule.timezone = String::from("Mars/Olympus");
+        assert!(build_probe_job(&schedule, None, RunningJobs::default(), get_test_store()).is_err());
+        schedule.timezone = String::from("UTC");
+        schedule.cron = String::from("every minute");
+        assert!(build_probe_job(&schedule, None, RunningJobs::default(), get_test_store()).is_err());
+        schedule.cron = String::from("0 * * * * *");
+        schedule.categories.push(String::from("Scarabs"));
+        assert!(build_probe_job(&schedule, None, RunningJobs::default(), get_test_store()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_categories_reports_failures() {
+        let failed = refresh_categories("Affliction", vec![String::from("Scarabs")], &get_test_store(), 2).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "Scarabs");
+        // a store error while writing fails the category too
+        let store = crate::init::get_offline_store().await;
+        store.set("Currency:Currency:filter", "Divine Orb").await.unwrap();
+        let store: SharedStore = Arc::new(store);
+        let failed = refresh_categories("Affliction", vec![String::from("Currency")], &store, 2).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "Currency");
+    }
+
+    #[tokio::test]
+    async fn test_get_the_refresh_key_map() {
+        let store = MemoryStore::new("local");
+        let _ = store.replace_list_expire("Data:Currency:Currency", &[String::from("{}")], 60).await;
+        let _ = store.replace_list_expire("Data:Item:Scarab", &[String::from("{}")], 60).await;
+        let _ = store.replace_list_expire("Data:Item:Essence", &[String::from("{}")], 60).await;
+        let mut refresh_key_map = get_the_refresh_key_map(&store).await.unwrap();
+        refresh_key_map.values_mut().for_each(|sub_types| sub_types.sort());
+        assert_eq!(refresh_key_map["Currency"], vec![String::from("Currency")]);
+        assert_eq!(refresh_key_map["Item"], vec![String::from("Essence"), String::from("Scarab")]);
     }
 }
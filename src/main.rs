@@ -1,9 +1,9 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post},
     Router
 };
 
-use crate::redis::RedisInstance;
+use crate::store::SharedStore;
 use std::io;
 use std::net::SocketAddr;
 use tracing::Level;
@@ -16,21 +16,33 @@ mod job_handler;
 mod discord;
 mod redis;
 mod init;
+mod alert_handler;
+mod enums;
+mod price_source;
+mod reminder_handler;
+mod filter_handler;
+mod store;
+mod sqlite;
+mod util;
 
 #[derive(Clone)]
 pub struct AppState {
     scheduler: JobScheduler,
-    redis: RedisInstance,
+    store: SharedStore,
     proflie: String,
+    running: job_handler::RunningJobs,
+    registering: job_handler::RegisterLock,
 }
 
 
 impl AppState {
-    fn new(scheduler: JobScheduler, redis: RedisInstance, profile: String) -> AppState {
+    fn new(scheduler: JobScheduler, store: SharedStore, profile: String) -> AppState {
         AppState {
             scheduler: scheduler,
-            redis: redis,
+            store: store,
             proflie: profile,
+            running: job_handler::RunningJobs::default(),
+            registering: job_handler::RegisterLock::default(),
         }
     }
 }
@@ -70,8 +82,8 @@ async fn main() {
 
     let profile = get_profile();
 
-    // initialize redis
-    let redis = RedisInstance::new(&profile);
+    // initialize the store, redis unless configured otherwise
+    let store = store::from_profile(&profile);
 
     // initialize scheduler
     let scheduler = JobScheduler::new().await;
@@ -86,14 +98,24 @@ async fn main() {
     }
 
     // initialize config
-    init::init_config(&profile, &redis).await;
+    init::init_config(&profile, store.as_ref()).await;
+    // convert the filter and skip lists of older versions
+    init::migrate_filter_lists(store.as_ref()).await;
     // initialize data
-    init::init_data(&redis).await;
+    init::init_data(store.as_ref()).await;
     // before start refresh data
-    init::init_call_before_start().await;
+    init::init_call_before_start(&store).await;
 
     // initialize app state
-    let app = AppState::new(scheduler.unwrap(), redis, profile);
+    let app = AppState::new(scheduler.unwrap(), store, profile);
+    // restore the jobs and reminders scheduled before the restart
+    let restored = job_handler::restore_jobs(&app).await + reminder_handler::restore_reminders(&app).await;
+    if restored > 0 {
+        match app.scheduler.start().await {
+            Ok(_) => tracing::info!("Scheduler started with {} restored jobs", restored),
+            Err(e) => tracing::error!("Scheduler failed to start: {}", e),
+        }
+    }
 
     // build our application with a route
     let app = Router::new()
@@ -101,11 +123,33 @@ async fn main() {
         .route("/hb", get(ninja_handler::hb))
         .route("/ninja_data", get(ninja_handler::get_data_from_ninja))
         .route("/filter_data", get(ninja_handler::get_filter_data))
-        .route("/add_filter", post(ninja_handler::add_data_filter))
-        .route("/add_skip_check", post(ninja_handler::add_skip_check))
+        .route("/history", get(ninja_handler::get_history))
+        .route("/threshold", get(ninja_handler::get_threshold_data).post(ninja_handler::set_threshold_data))
+        // Filter handler
+        .route("/add_filter", post(filter_handler::add_filter))
+        .route("/add_skip_check", post(filter_handler::add_skip_check))
+        .route("/filters", get(filter_handler::list_filters))
+        .route("/filters/:category", get(filter_handler::get_filters).put(filter_handler::replace_filters).delete(filter_handler::delete_filter_by_query))
+        .route("/filters/:category/:name", delete(filter_handler::delete_filter))
+        .route("/skip_lists", get(filter_handler::list_skip_lists))
+        .route("/skip_lists/:main_category", get(filter_handler::get_skip_list_data).put(filter_handler::replace_skip_list))
+        .route("/skip_lists/:main_category/:category", delete(filter_handler::delete_skip_check))
+        // Alert handler
+        .route("/alerts", get(alert_handler::list_alerts).post(alert_handler::create_alert))
+        .route("/alerts/:id", get(alert_handler::get_alert).put(alert_handler::update_alert).delete(alert_handler::delete_alert))
         // Job handler
         .route("/job/active", post(job_handler::active_probe_job))
         .route("/job/delete", post(job_handler::delete_probe_job))
+        .route("/jobs", get(job_handler::list_jobs))
+        .route("/jobs/:id", get(job_handler::get_job).delete(job_handler::delete_job))
+        .route("/jobs/:id/pause", post(job_handler::pause_job))
+        .route("/jobs/:id/resume", post(job_handler::resume_job))
+        .route("/jobs/:id/cancel", post(job_handler::cancel_job))
+        .route("/jobs/:id/run", post(job_handler::run_job))
+        .route("/report/run", post(job_handler::run_report_now))
+        // Reminder handler
+        .route("/reminders", get(reminder_handler::list_reminders).post(reminder_handler::create_reminder))
+        .route("/reminders/:id", get(reminder_handler::get_reminder).delete(reminder_handler::delete_reminder))
         .with_state(app);
 
     // run our app with hyper
@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::enums::Category;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueryResponse {
@@ -23,13 +26,16 @@ pub struct CurrencyDetails {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PaySparkLine {
-    // pub data: Vec<f64>,
+    // 7 days of change in percent, null when ninja has no data for the day
+    #[serde(default)]
+    pub data: Vec<Option<f64>>,
     pub totalChange: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReceiveSparkLine {
-    // pub data: Vec<f64>,
+    #[serde(default)]
+    pub data: Vec<Option<f64>>,
     pub totalChange: f64,
 }
 
@@ -65,6 +71,8 @@ pub struct ItemLine {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SparkLine {
+    #[serde(default)]
+    pub data: Vec<Option<f64>>,
     pub totalChange: f64,
 }
 
@@ -122,6 +130,11 @@ pub struct DataStore {
     pub divine_equivalent: f64,
     pub pay_total_change: f64,
     pub receive_total_change: f64,
+    // Sparkline data points, missing in snapshots stored before they were kept
+    #[serde(default)]
+    pub pay_spark_line: Vec<Option<f64>>,
+    #[serde(default)]
+    pub receive_spark_line: Vec<Option<f64>>,
     pub update_time: String,
 }
 
@@ -140,10 +153,40 @@ impl DataStore {
             divine_equivalent,
             pay_total_change,
             receive_total_change,
+            pay_spark_line: Vec::new(),
+            receive_spark_line: Vec::new(),
             update_time,
         }
     }
 
+    pub fn set_spark_lines(&mut self, pay_spark_line: Vec<Option<f64>>, receive_spark_line: Vec<Option<f64>>) {
+        self.pay_spark_line = pay_spark_line;
+        self.receive_spark_line = receive_spark_line;
+    }
+
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// One point of the price history, timestamp in unix seconds
+// divine_equivalent is null when the divine ratio was unknown at that time
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HistoryPoint {
+    pub timestamp: i64,
+    pub chaos_equivalent: f64,
+    pub divine_equivalent: Option<f64>,
+}
+
+impl HistoryPoint {
+    pub fn new(data: &DataStore, divine_ratio: f64, timestamp: i64) -> HistoryPoint {
+        HistoryPoint {
+            timestamp,
+            chaos_equivalent: data.chaos_equivalent,
+            divine_equivalent: (divine_ratio > 0.0).then_some(data.divine_equivalent),
+        }
+    }
+
     pub fn to_json_string(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
@@ -153,4 +196,339 @@ impl DataStore {
 pub struct AddFilterRequest {
     pub filter_type: String,
     pub name: String,
+    // only used by filters, skip lists always take category names
+    #[serde(default, rename = "type")]
+    pub kind: FilterKind,
+}
+
+// How a filter is matched against the names of a category
+// A glob matches the whole name with * and ?, a regex matches anywhere unless anchored
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    #[default]
+    Exact,
+    Glob,
+    Regex,
+}
+
+// Bulk replacement of the user filters of a category or the user skip list of a main category
+// The patterns are only replaced when given and only used by filters
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplaceFilterRequest {
+    pub names: Vec<String>,
+    #[serde(default)]
+    pub globs: Option<Vec<String>>,
+    #[serde(default)]
+    pub regexes: Option<Vec<String>>,
+}
+
+// Names tracked in a category, defaults come from default.toml and are rewritten on startup
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FilterList {
+    pub category: String,
+    pub defaults: Vec<String>,
+    pub names: Vec<String>,
+    pub globs: Vec<String>,
+    pub regexes: Vec<String>,
+}
+
+// Categories tracked entirely in a main category
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SkipList {
+    pub main_category: String,
+    pub defaults: Vec<String>,
+    pub categories: Vec<String>,
+}
+
+// Price range a line must fall in to be tracked when its whole category is tracked
+// Prices are inclusive, change is the absolute average of pay and receive total change in percent
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Threshold {
+    pub min_chaos: Option<f64>,
+    pub max_chaos: Option<f64>,
+    pub min_divine: Option<f64>,
+    pub max_divine: Option<f64>,
+    pub min_change: Option<f64>,
+}
+
+impl Threshold {
+    pub fn accept(&self, data: &DataStore) -> bool {
+        let change = ((data.pay_total_change + data.receive_total_change) / 2.0).abs();
+        !(self.min_chaos.is_some_and(|min| data.chaos_equivalent < min)
+            || self.max_chaos.is_some_and(|max| data.chaos_equivalent > max)
+            || self.min_divine.is_some_and(|min| data.divine_equivalent < min)
+            || self.max_divine.is_some_and(|max| data.divine_equivalent > max)
+            || self.min_change.is_some_and(|min| change < min))
+    }
+
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThresholdRequest {
+    pub category: String,
+    #[serde(flatten)]
+    pub threshold: Threshold,
+}
+
+// Fire when the price of an item crosses the target price in the direction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertDirection {
+    Above,
+    Below,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    pub id: String,
+    pub league: String,
+    pub category: String,
+    pub name: String,
+    pub direction: AlertDirection,
+    // Target price in chaos
+    pub price: f64,
+    // Minimum seconds between two messages of the alert
+    pub cooldown_secs: i64,
+    // Whether the condition held at the last evaluation, None before the first one
+    pub last_state: Option<bool>,
+    pub last_price: Option<f64>,
+    // Unix seconds of the last message
+    pub last_fired: Option<i64>,
+}
+
+impl Alert {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AlertRequest {
+    pub league: String,
+    pub category: String,
+    pub name: String,
+    pub direction: AlertDirection,
+    pub price: f64,
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: i64,
+}
+
+fn default_cooldown_secs() -> i64 {
+    3600
+}
+
+// Cron schedule of a probe job and the categories it refreshes and reports
+// An empty categories list means every category currently tracked in Redis
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleConfig {
+    pub name: String,
+    pub cron: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    // Hold a Redis lease for this long around each run so only one instance runs it, no lease if unset
+    #[serde(default)]
+    pub lease_ms: Option<u64>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> ScheduleConfig {
+        ScheduleConfig {
+            name: String::from("probe"),
+            cron: String::from("0 0 * * * *"),
+            timezone: default_timezone(),
+            categories: Vec::new(),
+            lease_ms: None,
+        }
+    }
+}
+
+impl ScheduleConfig {
+    // Group the categories by main category, None if the schedule lists none
+    pub fn get_category_map(&self) -> Result<Option<HashMap<String, Vec<String>>>, String> {
+        if self.categories.is_empty() {
+            return Ok(None);
+        }
+        let mut category_map: HashMap<String, Vec<String>> = HashMap::new();
+        for category in self.categories.iter() {
+            let category = category.parse::<Category>()?;
+            category_map
+                .entry(category.main_category().to_string())
+                .or_default()
+                .push(category.to_string());
+        }
+        Ok(Some(category_map))
+    }
+
+    // Same cron, timezone, categories and lease, the name and category order are ignored
+    pub fn is_equivalent(&self, other: &ScheduleConfig) -> bool {
+        let mut categories = self.categories.clone();
+        let mut other_categories = other.categories.clone();
+        categories.sort();
+        categories.dedup();
+        other_categories.sort();
+        other_categories.dedup();
+        self.cron.split_whitespace().eq(other.cron.split_whitespace())
+            && self.timezone == other.timezone
+            && categories == other_categories
+            && self.lease_ms == other.lease_ms
+    }
+}
+
+fn default_timezone() -> String {
+    String::from("UTC")
+}
+
+// One-off notification, fire_at is RFC 3339 in UTC
+// Sends the message and the reports of the categories, at least one of them is set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reminder {
+    pub id: String,
+    pub message: Option<String>,
+    pub categories: Vec<String>,
+    pub fire_at: String,
+    pub created_at: String,
+}
+
+impl Reminder {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// at is an RFC 3339 time or HH:MM in the timezone, delay_secs counts from now
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReminderRequest {
+    pub message: Option<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    pub at: Option<String>,
+    pub delay_secs: Option<i64>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+// Reports sent by a run and what failed to refresh or send
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReportResult {
+    pub sent: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ActiveJobRequest {
+    pub schedules: Vec<ScheduleConfig>,
+}
+
+// Registry entry of a scheduled job, times are UTC
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobRecord {
+    pub id: String,
+    #[serde(flatten)]
+    pub schedule: ScheduleConfig,
+    pub created_at: String,
+    // A paused job stays scheduled but skips its runs
+    #[serde(default)]
+    pub paused: bool,
+    pub last_run_at: Option<String>,
+    // "success", "failed" or "cancelled"
+    pub last_outcome: Option<String>,
+    pub last_error: Option<String>,
+    // Fencing token of the last leased run, outcomes of older runs are dropped
+    #[serde(default)]
+    pub fencing_token: Option<i64>,
+    // Read from the scheduler when the job is listed, not stored
+    #[serde(default, skip_deserializing)]
+    pub next_run_at: Option<String>,
+    #[serde(default, skip_deserializing)]
+    pub running: bool,
+    // Instance and fencing token holding the lease, read from Redis
+    #[serde(default, skip_deserializing)]
+    pub lease_holder: Option<String>,
+}
+
+impl JobRecord {
+    pub fn new(id: String, schedule: ScheduleConfig) -> JobRecord {
+        JobRecord {
+            id,
+            schedule,
+            created_at: chrono::offset::Utc::now().to_string(),
+            paused: false,
+            last_run_at: None,
+            last_outcome: None,
+            last_error: None,
+            fencing_token: None,
+            next_run_at: None,
+            running: false,
+            lease_holder: None,
+        }
+    }
+
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_is_equivalent() {
+        let schedule = ScheduleConfig {
+            name: String::from("item"),
+            cron: String::from("0 0 * * * *"),
+            timezone: String::from("UTC"),
+            categories: vec![String::from("Essence"), String::from("Invitation")],
+            lease_ms: None,
+        };
+        let mut other = schedule.clone();
+        other.name = String::from("other");
+        other.cron = String::from("0  0 * * * *");
+        other.categories.reverse();
+        assert!(schedule.is_equivalent(&other));
+        other.timezone = String::from("Asia/Taipei");
+        assert!(!schedule.is_equivalent(&other));
+        other.timezone = String::from("UTC");
+        other.categories.pop();
+        assert!(!schedule.is_equivalent(&other));
+        other.categories = schedule.categories.clone();
+        other.lease_ms = Some(60000);
+        assert!(!schedule.is_equivalent(&other));
+    }
+
+    #[test]
+    fn test_history_point() {
+        let mut data = DataStore::new(String::from("Divine Orb"), 180.0, 1.0, 2.0, -1.0, String::new());
+        data.set_spark_lines(vec![Some(0.0), None], vec![Some(1.5)]);
+        let point = HistoryPoint::new(&data, 180.0, 1704067200);
+        assert_eq!(point.to_json_string(), r#"{"timestamp":1704067200,"chaos_equivalent":180.0,"divine_equivalent":1.0}"#);
+        // the divine ratio was missing
+        let point = HistoryPoint::new(&data, 0.0, 1704067200);
+        let point: HistoryPoint = serde_json::from_str(&point.to_json_string()).unwrap();
+        assert_eq!((point.chaos_equivalent, point.divine_equivalent), (180.0, None));
+    }
+
+    #[test]
+    fn test_threshold_accept() {
+        let mut data = DataStore::new(String::from("Essence of Hysteria"), 18.0, 0.08, 4.0, -8.0, String::new());
+        assert!(Threshold::default().accept(&data));
+        let threshold = Threshold {
+            min_chaos: Some(10.0),
+            max_divine: Some(1.0),
+            min_change: Some(2.0),
+            ..Threshold::default()
+        };
+        assert!(threshold.accept(&data));
+        data.chaos_equivalent = 9.9;
+        assert!(!threshold.accept(&data));
+        data.chaos_equivalent = 18.0;
+        data.receive_total_change = -2.0;
+        assert!(!threshold.accept(&data));
+    }
 }
@@ -1,12 +1,18 @@
-use axum::{debug_handler, extract::{Query,State}, http::StatusCode, response::IntoResponse, Json};
-use reqwest;
+use axum::{extract::{Query,State}, http::StatusCode, response::IntoResponse, Json};
 use serde_derive::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashSet;
 
-use crate::models::{AddFilterRequest, DataStore, Line, ItemLine, QueryResponse, ItemQueryResponse, ApiResponse, self};
-use crate::redis::RedisInstance;
+use crate::enums::{Category, MainCategory};
+use crate::models::{DataStore, FilterKind, HistoryPoint, Threshold, ThresholdRequest, Line, ItemLine, QueryResponse, ItemQueryResponse, ApiResponse};
+use crate::alert_handler;
+use crate::price_source;
+use crate::store::Store;
+use crate::util::glob_match;
+use regex::{Regex, RegexSet};
+use redis::RedisResult;
 use crate::init::{get_config, Config};
-use crate::get_profile;
+use crate::AppState;
 // Structs
 #[derive(Deserialize, Debug)]
 pub struct QueryParams {
@@ -14,9 +20,33 @@ pub struct QueryParams {
     category: String,
 }
 
-impl QueryParams {
-    pub fn new(league: String, category: String) -> QueryParams {
-        QueryParams { league, category }
+#[derive(Deserialize, Debug)]
+pub struct HistoryParams {
+    league: String,
+    category: String,
+    name: String,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ThresholdParams {
+    category: String,
+}
+
+// Exact names and patterns a line of a category is tracked by
+#[derive(Debug)]
+pub struct Filter {
+    names: HashSet<String>,
+    globs: Vec<String>,
+    regexes: RegexSet,
+}
+
+impl Filter {
+    pub fn matches(&self, name: &str) -> bool {
+        self.names.contains(name)
+            || self.globs.iter().any(|glob| glob_match(glob, name))
+            || self.regexes.is_match(name)
     }
 }
 
@@ -28,294 +58,280 @@ pub async fn hb() -> impl IntoResponse {
 
 // Get data from ninja
 pub async fn get_data_from_ninja(
+    State(state): State<AppState>,
     query_params: Query<QueryParams>
-) -> (StatusCode, Json<ApiResponse>) {
+) -> Result<(StatusCode, Json<ApiResponse>), (StatusCode, Json<Value>)> {
     // Trace the request
     tracing::info!(
         "Requesting data from ninja: {} {}",
         query_params.league,
         query_params.category
     );
-    let profile = get_profile();
-    // Build the request
-    let api_response = request_data_from_ninja(
-        query_params.league.as_str(),
-        query_params.category.as_str(),
-    ).await;
+    let category = parse_category(&query_params.category)?;
+    let store = state.store.as_ref();
+    let api_response = match refresh_category(query_params.league.as_str(), category, store).await {
+        Ok(api_response) => api_response,
+        Err(e) => {
+            tracing::error!("Refresh {} {} error: {}", query_params.league, category, e);
+            ApiResponse::empty()
+        }
+    };
+    Ok((StatusCode::OK, Json(api_response)))
+}
+
+// Fetch a category, store it in Redis and evaluate its alerts
+pub async fn refresh_category(league: &str, category: Category, store: &dyn Store) -> Result<ApiResponse, String> {
+    let config: Config = get_config(store).await;
+    let source = price_source::from_config(&config);
+    let api_response = source.fetch(league, category).await?;
     // 根據 trait 類型
-    let main_category = get_query_type(query_params.category.to_string());
-    match main_category.as_str() {
-        "Currency" => {
+    match category.main_category() {
+        MainCategory::Currency => {
             if let Some(currency_response) = &api_response.currency_response {
-                write_to_redis(query_params.league.as_str(), &main_category, &query_params.category.to_string(), currency_response, profile.as_str());
+                write_to_redis(league, category, currency_response, &config, store)
+                    .await
+                    .map_err(|e| format!("Write {} error: {}", category, e))?;
             }
-            return (StatusCode::OK, Json(api_response));
         }
-        _ => {
+        MainCategory::Item => {
             if let Some(item_response) = &api_response.item_response {
-                write_to_redis_item(query_params.league.as_str(), main_category.as_str(), query_params.category.as_str(), item_response, profile.as_str());
-            }
-            return (StatusCode::OK, Json(api_response));
-        }
-    } 
-}
-
-// Add a dataFilter and return the filterList
-pub async fn add_data_filter(Json(payload): Json<AddFilterRequest>) -> (StatusCode, &'static str) {
-    let profile = get_profile();
-    let mut redis_instance = get_redis_instance(profile.as_str());
-    let filter_type = payload.filter_type;
-    let main_category = get_query_type(filter_type.to_string());
-    let name = payload.name;
-    let redis_key = format!("{}:{}:filter", main_category, filter_type);
-    let existance = redis_instance.exist_in_list(redis_key.as_str(), name.as_str());
-    // if exist skip else add
-    if existance.is_ok() && existance.unwrap() == true {
-        tracing::debug!("Filter {} already exists", redis_key);
-        (StatusCode::OK, "Already exists")
-    } else {
-        let res = redis_instance.push_list(&redis_key, &name);
-        match res {
-            Ok(_) => {
-                tracing::debug!("Filter {} {} added", redis_key, name);
-                (StatusCode::OK, "Ok!")
-            }
-            Err(e) => {
-                tracing::error!("Add filter error: {}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "Add filter failed!")
+                write_to_redis_item(league, category, item_response, &config, store)
+                    .await
+                    .map_err(|e| format!("Write {} error: {}", category, e))?;
             }
         }
     }
+    // Fire the alerts crossed by the new prices
+    alert_handler::evaluate_alerts(league, category, &api_response, store).await;
+    Ok(api_response)
 }
+
 // 取得 Redis 中的 filterList
 pub async fn get_filter_data(
+    State(state): State<AppState>,
     query_params: Query<QueryParams>,
-) -> (StatusCode, Json<Vec<DataStore>>) {
-    let profile = get_profile();
-    let data_list = get_current_data_list(query_params.category.as_str(), profile.as_str());
+) -> Result<(StatusCode, Json<Vec<DataStore>>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&query_params.category)?;
+    let store = state.store.as_ref();
+    let data_list = get_current_data_list(category, store).await;
     if data_list.len() != 0 {
-        return (StatusCode::OK, Json(data_list));
+        Ok((StatusCode::OK, Json(data_list)))
     }
     else {
         tracing::info!("No data in Redis");
-        return (StatusCode::OK, Json(Vec::new()));
+        Ok((StatusCode::OK, Json(Vec::new())))
     }
 }
-// Add a skip check 
-pub async fn add_skip_check(Json(payload): Json<AddFilterRequest>) -> (StatusCode, &'static str) {
-    let profile = get_profile();
-    let mut redis_instance = get_redis_instance(&profile);
-    // check if exists in list
-    let skip_key = format!("{}:skip", payload.filter_type);
-    let existance = redis_instance.exist_in_list(skip_key.as_str(), payload.name.as_str());
-    // if exist skip else add
-    if existance.is_ok() && existance.unwrap() == true {
-        tracing::debug!("Skip check {} already exists", skip_key);
-        (StatusCode::OK, "Already exists")
-    } else {
-        let res = redis_instance.push_list(&skip_key, &payload.name);
-        match res {
-            Ok(_) => {
-                tracing::debug!("Skip check {} {} added", skip_key, payload.name);
-                (StatusCode::OK, "Ok!")
-            }
-            Err(e) => {
-                tracing::debug!("Add skip check error: {}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "Add skip check failed!")
+// Get the threshold of a category
+pub async fn get_threshold_data(
+    State(state): State<AppState>,
+    query_params: Query<ThresholdParams>,
+) -> Result<(StatusCode, Json<Threshold>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&query_params.category)?;
+    let store = state.store.as_ref();
+    Ok((StatusCode::OK, Json(get_threshold(category, store).await)))
+}
+
+// Replace the threshold of a category
+pub async fn set_threshold_data(
+    State(state): State<AppState>,
+    Json(payload): Json<ThresholdRequest>,
+) -> Result<(StatusCode, Json<Threshold>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&payload.category)?;
+    let store = state.store.as_ref();
+    let res = set_threshold(category, &payload.threshold, store).await;
+    match res {
+        Ok(_) => {
+            tracing::debug!("Threshold {} set to {:?}", category, payload.threshold);
+            Ok((StatusCode::OK, Json(payload.threshold)))
+        }
+        Err(e) => {
+            tracing::error!("Set threshold error: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Set threshold failed!" }))))
+        }
+    }
+}
+
+// Get the price history of an item between from and to (unix seconds)
+pub async fn get_history(
+    State(state): State<AppState>,
+    query_params: Query<HistoryParams>,
+) -> Result<(StatusCode, Json<Vec<HistoryPoint>>), (StatusCode, Json<Value>)> {
+    let category = parse_category(&query_params.category)?;
+    let store = state.store.as_ref();
+    let history_key = get_history_key(&query_params.league, category, &query_params.name);
+    let from = query_params.from.unwrap_or(i64::MIN);
+    let to = query_params.to.unwrap_or(i64::MAX);
+    let res = store.get_sorted_set_by_score(&history_key, from, to).await;
+    match res {
+        Ok(res) => {
+            let mut history: Vec<HistoryPoint> = Vec::new();
+            for (data, timestamp) in res {
+                match serde_json::from_str::<HistoryPoint>(&data) {
+                    Ok(point) => history.push(HistoryPoint { timestamp, ..point }),
+                    Err(e) => tracing::error!("Parse history error: {}", e),
+                }
             }
+            Ok((StatusCode::OK, Json(history)))
+        }
+        Err(e) => {
+            tracing::error!("Get history error: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Get history failed!" }))))
         }
     }
 }
-// Get the filterList and format to the output format
-pub fn get_format_output(category: &str, profile: &str) -> String {
-    let currency_list = get_all_category("Data:Currency:*", &profile);
-    let item_list = get_all_category("Data:Item:*", &profile);
+// Get the filterList of the sub categories and format to the output format
+pub async fn get_format_output(category: &str, sub_categories: &[String], store: &dyn Store) -> String {
     // Format the output to Discord
     let mut output = String::new();
     if category == "Currency" {
         // Currency header
         output.push_str("# Currency\n");
-        // Build the currency table
-        for c in currency_list.iter() {
-            build_output_str(&mut output, &c, &profile);
-        }
     }
     else {
         // Item header
         output.push_str("# Item\n");
-        for i in item_list.iter() {
-            build_output_str(&mut output, &i, &profile);
-        }
+    }
+    // Build the table of each sub category
+    for c in sub_categories.iter() {
+        build_output_str(&mut output, c, store).await;
     }
     output
 }
 
-// get data from ninja
-pub async fn request_data_from_ninja(league: &str, category: &str) -> ApiResponse{
-    // Build the request
-    let client = reqwest::Client::new();
-    let main_category = get_query_type(category.to_string());
-    let url = format!(
-        "{}?league={}&type={}",
-        get_base_url_from_toml(category.to_string()).await,
-        league,
-        category
-    );
-    tracing::debug!("URL: {}", url);
-    let res = client.get(&url).send().await;
+// get data from the configured price source
+pub async fn request_data_from_ninja(league: &str, category: Category, store: &dyn Store) -> ApiResponse{
+    let config: Config = get_config(store).await;
+    let source = price_source::from_config(&config);
+    let res = source.fetch(league, category).await;
     match res {
-        Ok(res) => {
-            let content = res.text().await;
-            if main_category == "Currency" {
-                let res_json: Result<QueryResponse, _> = serde_json::from_str(&content.unwrap());
-                match res_json {
-                    Ok(res_json) => {
-                        let mut api_response = ApiResponse::empty();
-                        api_response.set_currency_response(res_json);
-                        return api_response;
-                    }
-                    Err(e) => {
-                        tracing::error!("Parse data error: {}", e);
-                        return ApiResponse::empty();
-                    }
-                }
-            }
-            else {
-                let res_json: Result<ItemQueryResponse, _> = serde_json::from_str(&content.unwrap());
-                match res_json {
-                    Ok(res_json) => {
-                        let mut api_response = ApiResponse::empty();
-                        api_response.set_item_response(res_json);
-                        return api_response;
-                    }
-                    Err(e) => {
-                        tracing::error!("Parse data error: {}", e);
-                        return ApiResponse::empty();
-                    }
-                }
-            }
-        }
+        Ok(api_response) => api_response,
         Err(e) => {
-            tracing::error!("Get data error: {}", e);
-            return ApiResponse::empty();
+            tracing::error!("Fetch {} {} error: {}", league, category, e);
+            ApiResponse::empty()
         }
     }
 }
 
-// Private functions
-fn get_query_type(category: String) -> String {
-    match category.as_str() {
-        "Currency" => "Currency",
-        "Fragment" => "Currency",
-        _ => "Item",
-    }
-    .to_string()
+// Parse a category from request input, listing the valid values on failure
+pub fn parse_category(category: &str) -> Result<Category, (StatusCode, Json<Value>)> {
+    category.parse::<Category>().map_err(|e| {
+        tracing::debug!("Reject request: {}", e);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": e,
+                "valid_values": Category::names(),
+            })),
+        )
+    })
 }
 
-async fn get_base_url_from_toml(category: String) -> String {
-    let profile = get_profile();
-    let config: Config = get_config(&profile).await;
-    match category.as_str() {
-        "Currency" => config.ninja.currency,
-        "Fragment" => config.ninja.currency,
-        _ => config.ninja.item,
-    }
+// Answer a store failure with 500, the error itself is only logged
+pub fn internal_error(e: redis::RedisError) -> (StatusCode, Json<Value>) {
+    tracing::error!("Redis execution error: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Redis execution error" })))
+}
+
+pub fn bad_request(e: String) -> (StatusCode, Json<Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": e })))
 }
 
+// Private functions
 // Store the query data to redis
-fn write_to_redis(league: &str, main_category: &str, category: &str, query_res: &QueryResponse, profile: &str) {
-    // Initialize Redis
-    let mut redis_instance = get_redis_instance(&profile);
-    let mut data_list: Vec<String> = Vec::new();
-    let mut current_list = get_current_data_list(category, &profile);
+async fn write_to_redis(league: &str, category: Category, query_res: &QueryResponse, config: &Config, store: &dyn Store) -> RedisResult<()> {
+    let main_category = category.main_category();
+    let mut data_list: Vec<DataStore> = Vec::new();
+    let mut current_list = get_current_data_list(category, store).await;
+    let threshold = get_threshold(category, store).await;
     let query_res = query_res.clone();
+    let redis_key = get_filter_key(category);
+    // Load the filter and skip membership once for the whole response
+    let filter = get_filter(category, store).await?;
+    // Check if the category is in skip list, if yes skip filter
+    let skip_filter = is_skipped(category, store).await?;
+    // Read the divine ratio once for the whole response, a fresh Divine Orb price replaces it first
+    let divine_ratio = match query_res.lines.iter().find(|line| line.currencyTypeName == "Divine Orb") {
+        Some(line) => {
+            set_divine_to_chaos_ratio(league, line.chaosEquivalent, store).await;
+            line.chaosEquivalent
+        }
+        None => get_divine_to_chaos_ratio(league, store).await,
+    };
+    let now = chrono::offset::Utc::now().timestamp();
+    let mut history: Vec<(String, HistoryPoint)> = Vec::new();
     for line in query_res.lines {
-        // Match the currency
-        let redis_key = format!("{}:{}:filter", main_category, category);
-        // Check if the key exists
-        let existance = redis_instance.exist_in_list(&redis_key, &line.currencyTypeName);
-        let skip_key = format!("{}:skip", main_category);
-        // Check if the category is in skip list, if yes skip filter
-        let skip_filter = redis_instance.exist_in_list(&skip_key , &category);
+        // Check if the name is in the filter
+        let existance = filter.matches(&line.currencyTypeName);
         // parse to DataStore
-        let data = parse_data_line_to_datastore(league, line, &redis_key, &mut redis_instance);
+        let data = parse_data_line_to_datastore(line, divine_ratio, &redis_key);
+        history.push((data.name.clone(), HistoryPoint::new(&data, divine_ratio, now)));
         // if exist update else skip
-        match existance {
-            Ok(existance) => {
-                if existance == true {
-                    tracing::debug!("{} {} exists", category, redis_key);
-                    // If exist in current list skip
-                    if exist_in_list(&current_list, &data.name) {
-                        tracing::debug!("{} {} exists in current list pop first", category, &data.name);
-                        pop_by_name(&mut current_list, &data.name);
-                    }
-                    data_list.push(data.to_json_string());
-                } else {
-                    // Check if the currency is in skip list, if yes skip filter
-                    match skip_filter {
-                        Ok(skip_filter) => {
-                            if skip_filter == true {
-                                tracing::debug!("{} exists in skip filter list", category);
-                                // If exist in current list update it
-                                if exist_in_list(&current_list, &data.name) {
-                                    tracing::debug!("{} {} exists in current list pop first", category, &data.name);
-                                    pop_by_name(&mut current_list, &data.name);
-                                }
-                                data_list.push(data.to_json_string());
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Redis execution error: {}", e);
-                            continue;
-                        }
-                    }
-                }
+        if existance {
+            tracing::debug!("{} {} exists", category, redis_key);
+            // If exist in current list skip
+            if exist_in_list(&current_list, &data.name) {
+                tracing::debug!("{} {} exists in current list pop first", category, &data.name);
+                pop_by_name(&mut current_list, &data.name);
+            }
+            data_list.push(data);
+        } else if skip_filter {
+            tracing::debug!("{} exists in skip filter list", category);
+            // If exist in current list update it
+            if exist_in_list(&current_list, &data.name) {
+                tracing::debug!("{} {} exists in current list pop first", category, &data.name);
+                pop_by_name(&mut current_list, &data.name);
             }
-            Err(e) => {
-                tracing::error!("Redis execution error: {}", e);
-                continue;
+            if threshold.accept(&data) {
+                data_list.push(data);
+            }
+            else {
+                tracing::debug!("{} priced {} chaos out of {} threshold", data.name, data.chaos_equivalent, category);
             }
         }
     }
+    // Append every fetched item to the price history, tracked or not
+    write_history(league, category, &history, now, config.history.retention_days, store).await;
     if data_list.len() == 0 {
         tracing::debug!("No data to write");
-        return;
+        return Ok(());
     }
+    let data_list: Vec<String> = data_list.iter().map(|data| data.to_json_string()).collect();
     // Write to Redis
     let redis_key = format!("Data:{}:{}", main_category, category);
-    // Remove all first
-    let _ = redis_instance.delete(&redis_key);
-    let res = redis_instance.set_list_expire(redis_key.as_str(), &data_list, 3600);
+    // Swap the snapshot in at once so readers never see it half written
+    let res = store.replace_list_expire(redis_key.as_str(), &data_list, 3600).await;
     match res {
         Ok(_) => {
             tracing::debug!("{} data written to Redis", category);
+            Ok(())
         }
         Err(e) => {
             tracing::error!("Write to Redis error: {}", e);
+            Err(e)
         }
     }
 }
 
-fn parse_data_line_to_datastore(league: &str, line: Line, redis_key: &str, redis_instance: &mut RedisInstance) -> DataStore {
-    // if sparkline exists get the totalChange or 0
-    let pay_total_change = match line.paySparkLine {
-        Some(pay_spark_line) => pay_spark_line.totalChange,
+fn parse_data_line_to_datastore(line: Line, divine_ratio: f64, redis_key: &str) -> DataStore {
+    // if sparkline exists get the totalChange and data points or 0 and empty
+    let (pay_total_change, pay_spark_line) = match line.paySparkLine {
+        Some(pay_spark_line) => (pay_spark_line.totalChange, pay_spark_line.data),
         None => {
             tracing::debug!("{} paySparkLine not exists", redis_key);
-            0.0
+            (0.0, Vec::new())
         }
     };
-    let receive_total_change = match line.receiveSparkLine {
-        Some(receive_spark_line) => receive_spark_line.totalChange,
+    let (receive_total_change, receive_spark_line) = match line.receiveSparkLine {
+        Some(receive_spark_line) => (receive_spark_line.totalChange, receive_spark_line.data),
         None => {
             tracing::debug!("{} receiveSparkLine not exists", redis_key);
-            0.0
+            (0.0, Vec::new())
         }
     };
-    let mut divine_equivalent = get_divine_to_chaos_ratio(league, redis_instance);
-    divine_equivalent = line.chaosEquivalent / divine_equivalent;
+    let divine_equivalent = get_divine_equivalent(line.chaosEquivalent, divine_ratio);
     // Build the data
-    let data = DataStore::new(
+    let mut data = DataStore::new(
         line.currencyTypeName,
         line.chaosEquivalent,
         divine_equivalent,
@@ -323,105 +339,88 @@ fn parse_data_line_to_datastore(league: &str, line: Line, redis_key: &str, redis
         receive_total_change,
         chrono::offset::Utc::now().to_string(),
     );
-    if data.name == "Divine Orb" {
-        // delete first 
-        let d2c_key = format!("{}:D2C", &league);
-        let _ = redis_instance.delete(&d2c_key);
-        let _ = redis_instance.push_hash_expire(&d2c_key,"ratio", &data.chaos_equivalent.to_string(), 3600);
-        let _ = redis_instance.push_hash_expire(&d2c_key,"update_time", &chrono::offset::Utc::now().to_string(), 3600);
-    }
+    data.set_spark_lines(pay_spark_line, receive_spark_line);
     data
 }
 
 // write to Redis item flow
-fn write_to_redis_item(league: &str, main_category: &str, category: &str, query_res: &ItemQueryResponse, profile: &str) {
-    let mut redis_instance = get_redis_instance(&profile);
-    let mut data_list: Vec<String> = Vec::new();
-    let mut current_list = get_current_data_list(category, &profile);
+async fn write_to_redis_item(league: &str, category: Category, query_res: &ItemQueryResponse, config: &Config, store: &dyn Store) -> RedisResult<()> {
+    let main_category = category.main_category();
+    let mut data_list: Vec<DataStore> = Vec::new();
+    let mut current_list = get_current_data_list(category, store).await;
+    let threshold = get_threshold(category, store).await;
     let query_res = query_res.clone();
+    let redis_key = get_filter_key(category);
+    // Load the filter and skip membership once for the whole response
+    let filter = get_filter(category, store).await?;
+    // Check if the category is in skip list, if yes skip filter
+    let skip_filter = is_skipped(category, store).await?;
+    // Read the divine ratio once for the whole response
+    let divine_ratio = get_divine_to_chaos_ratio(league, store).await;
+    let now = chrono::offset::Utc::now().timestamp();
+    let mut history: Vec<(String, HistoryPoint)> = Vec::new();
     for line in query_res.lines {
-        // Match the currency
-        let redis_key = format!("{}:{}:filter", main_category, category);
-        // Check if the key exists
-        let existance = redis_instance.exist_in_list(&redis_key, &line.name);
-        let skip_key = format!("{}:skip", main_category);
-        // Check if the category is in skip list, if yes skip filter
-        let skip_filter = redis_instance.exist_in_list(&skip_key , &category);
+        // Check if the name is in the filter
+        let existance = filter.matches(&line.name);
         // parse to DataStore
-        let data = parse_data_line_to_datastore_item(league, line, &redis_key, &mut redis_instance);
+        let data = parse_data_line_to_datastore_item(line, divine_ratio, &redis_key);
+        history.push((data.name.clone(), HistoryPoint::new(&data, divine_ratio, now)));
         // if exist update else skip
-        match existance {
-            Ok(existance) => {
-                if existance == true {
-                    tracing::info!("{} {} exists", category, redis_key);
-                    // If exist in current list skip
-                    if exist_in_list(&current_list, &data.name) {
-                        tracing::info!("{} {} exists in current list pop first", category, &data.name);
-                        pop_by_name(&mut current_list, &data.name);
-                    }
-                    data_list.push(data.to_json_string());
-                } else {
-                    // Check if the currency is in skip list, if yes skip filter
-                    match skip_filter {
-                        Ok(skip_filter) => {
-                            if skip_filter == true {
-                                tracing::info!("{} exists in skip filter list", category);
-                                // If exist in current list update it
-                                if exist_in_list(&current_list, &data.name) {
-                                    tracing::info!("{} {} exists in current list pop first", category, &data.name);
-                                    pop_by_name(&mut current_list, &data.name);
-                                }
-                                // TODO: Set in config
-                                if data.chaos_equivalent > 10.0 {
-                                    data_list.push(data.to_json_string());
-                                }
-                                else {
-                                    tracing::debug!("{} priced {} chaos too cheap to skip", data.name, data.chaos_equivalent);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Redis execution error: {}", e);
-                            continue;
-                        }
-                    }
-                }
+        if existance {
+            tracing::info!("{} {} exists", category, redis_key);
+            // If exist in current list skip
+            if exist_in_list(&current_list, &data.name) {
+                tracing::info!("{} {} exists in current list pop first", category, &data.name);
+                pop_by_name(&mut current_list, &data.name);
             }
-            Err(e) => {
-                tracing::error!("Redis execution error: {}", e);
-                continue;
+            data_list.push(data);
+        } else if skip_filter {
+            tracing::info!("{} exists in skip filter list", category);
+            // If exist in current list update it
+            if exist_in_list(&current_list, &data.name) {
+                tracing::info!("{} {} exists in current list pop first", category, &data.name);
+                pop_by_name(&mut current_list, &data.name);
+            }
+            if threshold.accept(&data) {
+                data_list.push(data);
+            }
+            else {
+                tracing::debug!("{} priced {} chaos out of {} threshold", data.name, data.chaos_equivalent, category);
             }
         }
     }
+    // Append every fetched item to the price history, tracked or not
+    write_history(league, category, &history, now, config.history.retention_days, store).await;
+    let data_list: Vec<String> = data_list.iter().map(|data| data.to_json_string()).collect();
     // Write to Redis
     let redis_key = format!("Data:{}:{}", main_category, category);
-    // Remove all first
-    let _ = redis_instance.delete(&redis_key);
-    let res = redis_instance.set_list_expire(redis_key.as_str(), &data_list, 3600);
+    // Swap the snapshot in at once so readers never see it half written
+    let res = store.replace_list_expire(redis_key.as_str(), &data_list, 3600).await;
     match res {
         Ok(_) => {
             tracing::info!("{} data written to Redis", category);
+            Ok(())
         }
         Err(e) => {
             tracing::error!("Write to Redis error: {}", e);
+            Err(e)
         }
     }
 }
 
 // parse item line to DataStore
-fn parse_data_line_to_datastore_item(league: &str, line: ItemLine, redis_key: &str, redis_instance: &mut RedisInstance) -> DataStore {
-    // if sparkline exists get the totalChange or 0
-    let total_change = match line.sparkline {
-        Some(spark_line) => spark_line.totalChange,
+fn parse_data_line_to_datastore_item(line: ItemLine, divine_ratio: f64, redis_key: &str) -> DataStore {
+    // if sparkline exists get the totalChange and data points or 0 and empty
+    let (total_change, spark_line) = match line.sparkline {
+        Some(spark_line) => (spark_line.totalChange, spark_line.data),
         None => {
             tracing::info!("{} sparkLine not exists", redis_key);
-            0.0
+            (0.0, Vec::new())
         }
     };
-    let mut divine_equivalent = get_divine_to_chaos_ratio(league, redis_instance);
-    divine_equivalent = line.chaosValue / divine_equivalent;
+    let divine_equivalent = get_divine_equivalent(line.chaosValue, divine_ratio);
     // Build the data
-    let data = DataStore::new(
+    let mut data = DataStore::new(
         line.name.clone(),
         line.chaosValue,
         divine_equivalent,
@@ -429,23 +428,118 @@ fn parse_data_line_to_datastore_item(league: &str, line: ItemLine, redis_key: &s
         total_change,
         chrono::offset::Utc::now().to_string(),
     );
+    data.set_spark_lines(spark_line.clone(), spark_line);
     data
 }
 
-// Get Redis instance
-fn get_redis_instance(profile: &str) -> RedisInstance {
-    let redis_instance = RedisInstance::new(profile);
-    redis_instance
+// Append the price point of each item to its history and trim the expired points, in one batch per refresh
+async fn write_history(league: &str, category: Category, history: &[(String, HistoryPoint)], now: i64, retention_days: i64, store: &dyn Store) {
+    let members: Vec<(String, String)> = history
+        .iter()
+        .map(|(name, point)| (get_history_key(league, category, name), point.to_json_string()))
+        .collect();
+    let res = store.push_sorted_sets(&members, now, now - retention_days * 24 * 3600).await;
+    match res {
+        Ok(_) => tracing::debug!("{} history appended for {} items", category, history.len()),
+        Err(e) => tracing::error!("Write {} history error: {}", category, e),
+    }
+}
+
+fn get_history_key(league: &str, category: Category, name: &str) -> String {
+    format!("History:{}:{}:{}", league, category, name)
+}
+
+// A threshold set through the API is kept for good, the default is rewritten from default.toml on startup
+pub fn get_threshold_key(category: Category) -> String {
+    format!("{}:{}:threshold", category.main_category(), category)
+}
+
+pub fn get_default_threshold_key(category: Category) -> String {
+    format!("{}:default", get_threshold_key(category))
+}
+
+// Get the threshold of a category, the one set through the API over the default, accept everything if neither is set
+pub async fn get_threshold(category: Category, store: &dyn Store) -> Threshold {
+    for threshold_key in [get_threshold_key(category), get_default_threshold_key(category)] {
+        if let Ok(res) = store.get(&threshold_key).await {
+            return serde_json::from_str(&res).unwrap_or_else(|e| {
+                tracing::error!("Parse threshold {} error: {}", threshold_key, e);
+                Threshold::default()
+            });
+        }
+    }
+    Threshold::default()
+}
+
+pub async fn set_threshold(category: Category, threshold: &Threshold, store: &dyn Store) -> RedisResult<()> {
+    store.set(&get_threshold_key(category), &threshold.to_json_string()).await
+}
+
+// User filters are kept for good, the defaults are rewritten from default.toml on startup
+pub fn get_filter_key(category: Category) -> String {
+    format!("{}:{}:filter", category.main_category(), category)
+}
+
+pub fn get_default_filter_key(category: Category) -> String {
+    format!("{}:default", get_filter_key(category))
+}
+
+// The exact names and each kind of pattern are kept in their own set
+pub fn get_filter_kind_key(category: Category, kind: FilterKind) -> String {
+    match kind {
+        FilterKind::Exact => get_filter_key(category),
+        FilterKind::Glob => format!("{}:glob", get_filter_key(category)),
+        FilterKind::Regex => format!("{}:regex", get_filter_key(category)),
+    }
+}
+
+pub fn get_skip_key(main_category: MainCategory) -> String {
+    format!("{}:skip", main_category)
+}
+
+pub fn get_default_skip_key(main_category: MainCategory) -> String {
+    format!("{}:default", get_skip_key(main_category))
+}
+
+// Get the names and patterns tracked for a category, the defaults merged with the user filters
+pub async fn get_filter(category: Category, store: &dyn Store) -> RedisResult<Filter> {
+    let mut names = store.get_set(&get_default_filter_key(category)).await?;
+    names.extend(store.get_set(&get_filter_kind_key(category, FilterKind::Exact)).await?);
+    let globs = store.get_set(&get_filter_kind_key(category, FilterKind::Glob)).await?.into_iter().collect();
+    // Every regex of the category is compiled into one set, once per refresh
+    let patterns: Vec<String> = store.get_set(&get_filter_kind_key(category, FilterKind::Regex)).await?.into_iter().collect();
+    let regexes = match RegexSet::new(&patterns) {
+        Ok(regexes) => regexes,
+        Err(_) => {
+            // A pattern stored outside the API can be invalid, skip it and keep the others
+            let patterns = patterns.iter().filter(|pattern| match Regex::new(pattern) {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::error!("Invalid {} filter regex {}: {}", category, pattern, e);
+                    false
+                }
+            });
+            RegexSet::new(patterns).unwrap_or_else(|_| RegexSet::empty())
+        }
+    };
+    Ok(Filter { names, globs, regexes })
 }
+
+// Check if the whole category is tracked, by default or by a user
+pub async fn is_skipped(category: Category, store: &dyn Store) -> RedisResult<bool> {
+    let main_category = category.main_category();
+    Ok(store.exist_in_set(&get_default_skip_key(main_category), category.as_str()).await?
+        || store.exist_in_set(&get_skip_key(main_category), category.as_str()).await?)
+}
+
 // Get current data list from Redis
-fn get_current_data_list(category: &str, profile: &str) -> Vec<DataStore> {
-    let mut redis_instance = get_redis_instance(profile);
+async fn get_current_data_list(category: Category, store: &dyn Store) -> Vec<DataStore> {
     let redis_key = format!(
         "Data:{}:{}",
-        get_query_type(category.to_string()),
+        category.main_category(),
         category
     );
-    let res = redis_instance.get_list(redis_key.as_str());
+    let res = store.get_list(redis_key.as_str()).await;
     match res {
         Ok(_) => {
             // Parse to DataStore
@@ -493,23 +587,16 @@ fn pop_by_name(list: &mut Vec<DataStore>, value: &String) {
     list.remove(index);
 }
 
-// Get all category from Redis
-fn get_all_category(key: &str, profile: &str) -> Vec<String> {
-    let mut redis_instance = get_redis_instance(&profile);
-    let category_list = redis_instance.get_all_keys_name(key);
-    match category_list {
-        Ok(category_list) => {
-            return category_list;
-        }
+// Build the output string
+async fn build_output_str(output: &mut String, category: &str, store: &dyn Store) {
+    let category = match category.parse::<Category>() {
+        Ok(category) => category,
         Err(e) => {
-            tracing::error!("Get all category error: {}", e);
-            return Vec::new();
+            tracing::error!("Skip output: {}", e);
+            return;
         }
-    }
-}
-// Build the output string
-fn build_output_str(output: &mut String, category: &str, profile: &str) {
-    let data_list = get_current_data_list(category,profile);
+    };
+    let data_list = get_current_data_list(category, store).await;
 
     // Markdown header
     output.push_str(format!("## **{}**\n", category).as_str());
@@ -531,9 +618,25 @@ fn build_output_str(output: &mut String, category: &str, profile: &str) {
     }
 }
 
+// Price in divine, 0 when the ratio is missing so the data never holds an infinite value
+fn get_divine_equivalent(chaos_equivalent: f64, divine_ratio: f64) -> f64 {
+    if divine_ratio > 0.0 {
+        chaos_equivalent / divine_ratio
+    } else {
+        0.0
+    }
+}
+
+// Overwrite the divine to chaos ratio, both fields expire with the data
+async fn set_divine_to_chaos_ratio(league: &str, ratio: f64, store: &dyn Store) {
+    let d2c_key = format!("{}:D2C", &league);
+    let _ = store.push_hash_expire(&d2c_key,"ratio", &ratio.to_string(), 3600).await;
+    let _ = store.push_hash_expire(&d2c_key,"update_time", &chrono::offset::Utc::now().to_string(), 3600).await;
+}
+
 // Get divine to chaos ratio
-fn get_divine_to_chaos_ratio(league: &str, redis: &mut RedisInstance) -> f64 {
-    let divine_to_chaos = redis.get_hash(format!("{}:D2C", league).as_str(), "ratio");
+async fn get_divine_to_chaos_ratio(league: &str, store: &dyn Store) -> f64 {
+    let divine_to_chaos = store.get_hash(format!("{}:D2C", league).as_str(), "ratio").await;
     match divine_to_chaos {
         Ok(divine_to_chaos) => {
             let divine_to_chaos: f64 = divine_to_chaos.parse().unwrap();
@@ -550,12 +653,50 @@ fn get_divine_to_chaos_ratio(league: &str, redis: &mut RedisInstance) -> f64 {
 #[cfg(test)]
 #[tokio::test]
 async fn test_get_data_from_ninja() {
-    let league = String::from("Affliction");
-    let category = String::from("Currency");
-    let query_params = QueryParams { league, category };
-    let (status, response) = get_data_from_ninja(Query(query_params)).await;
-    assert_eq!(status, StatusCode::OK);
-    let output = get_format_output("Currency", "local");
+    let store = crate::init::get_offline_store().await;
+    crate::init::init_data(&store).await;
+    let response = refresh_category("Affliction", Category::Currency, &store).await;
+    assert!(response.is_ok());
+    let output = get_format_output("Currency", &[String::from("Currency")], &store).await;
+    let history = store.get_sorted_set_by_score("History:Affliction:Currency:Divine Orb", i64::MIN, i64::MAX).await.unwrap();
+    assert_eq!(history.len(), 1);
 
     assert_ne!(output, "");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_filter() {
+    let store = crate::store::MemoryStore::new("local");
+    store.push_set("Item:Scarab:filter:default", "Titanic Scarab").await.unwrap();
+    store.push_set("Item:Scarab:filter", "Gilded Scarab").await.unwrap();
+    store.push_set("Item:Scarab:filter:glob", "* Scarab of *").await.unwrap();
+    store.push_set("Item:Scarab:filter:regex", "^Winged").await.unwrap();
+    store.push_set("Item:Scarab:filter:regex", "(").await.unwrap();
+    let filter = get_filter(Category::Scarab, &store).await.unwrap();
+    assert!(filter.matches("Titanic Scarab"));
+    assert!(filter.matches("Gilded Scarab"));
+    assert!(filter.matches("Polished Scarab of Divination"));
+    assert!(filter.matches("Winged Ambush Scarab"));
+    assert!(!filter.matches("Rusted Scarab"));
+    assert!(!filter.matches("Scarab of Divination"));
+    assert!(!is_skipped(Category::Scarab, &store).await.unwrap());
+    store.push_set("Item:skip", "Scarab").await.unwrap();
+    assert!(is_skipped(Category::Scarab, &store).await.unwrap());
+    store.delete("Item:skip").await.unwrap();
+    store.push_set("Item:skip:default", "Scarab").await.unwrap();
+    assert!(is_skipped(Category::Scarab, &store).await.unwrap());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_threshold() {
+    let store = crate::store::MemoryStore::new("local");
+    assert!(get_threshold(Category::Essence, &store).await.min_chaos.is_none());
+    let default = Threshold { min_chaos: Some(10.0), ..Threshold::default() };
+    store.set("Item:Essence:threshold:default", &default.to_json_string()).await.unwrap();
+    assert_eq!(get_threshold(Category::Essence, &store).await.min_chaos, Some(10.0));
+    let threshold = Threshold { max_divine: Some(5.0), ..Threshold::default() };
+    set_threshold(Category::Essence, &threshold, &store).await.unwrap();
+    let threshold = get_threshold(Category::Essence, &store).await;
+    assert_eq!((threshold.min_chaos, threshold.max_divine), (None, Some(5.0)));
+}
@@ -0,0 +1,154 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+
+use crate::init::Config;
+use crate::models::{ApiResponse, ItemQueryResponse, QueryResponse};
+use crate::enums::{Category, MainCategory};
+
+// Where the price overview of a league/category comes from
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    async fn fetch(&self, league: &str, category: Category) -> Result<ApiResponse, String>;
+}
+
+// Live poe.ninja api
+pub struct NinjaSource {
+    client: reqwest::Client,
+    currency_url: String,
+    item_url: String,
+}
+
+impl NinjaSource {
+    pub fn new(currency_url: String, item_url: String) -> NinjaSource {
+        NinjaSource {
+            client: reqwest::Client::new(),
+            currency_url,
+            item_url,
+        }
+    }
+}
+
+#[async_trait]
+impl PriceSource for NinjaSource {
+    async fn fetch(&self, league: &str, category: Category) -> Result<ApiResponse, String> {
+        let base_url = match category.main_category() {
+            MainCategory::Currency => &self.currency_url,
+            MainCategory::Item => &self.item_url,
+        };
+        let url = format!("{}?league={}&type={}", base_url, league, category);
+        tracing::debug!("URL ({}): {}", category.main_category().endpoint(), url);
+        let res = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| format!("Get data error: {}", e))?;
+        let content = res
+            .text()
+            .await
+            .map_err(|e| format!("Read data error: {}", e))?;
+        parse_response(category, &content)
+    }
+}
+
+// Recorded responses on disk, laid out as {path}/{league}/{category}.json
+// with {path}/{category}.json as a league independent fallback
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> FileSource {
+        FileSource {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+#[async_trait]
+impl PriceSource for FileSource {
+    async fn fetch(&self, league: &str, category: Category) -> Result<ApiResponse, String> {
+        let file_name = format!("{}.json", category);
+        let league_file = self.path.join(league).join(&file_name);
+        let file = if league_file.exists() {
+            league_file
+        } else {
+            self.path.join(&file_name)
+        };
+        tracing::debug!("File: {}", file.display());
+        let content = tokio::fs::read_to_string(&file)
+            .await
+            .map_err(|e| format!("Read {} error: {}", file.display(), e))?;
+        parse_response(category, &content)
+    }
+}
+
+// Build the price source selected in config
+pub fn from_config(config: &Config) -> Box<dyn PriceSource> {
+    match config.source.kind.as_str() {
+        "file" => Box::new(FileSource::new(&config.source.path)),
+        "ninja" => Box::new(NinjaSource::new(
+            config.ninja.currency.clone(),
+            config.ninja.item.clone(),
+        )),
+        other => {
+            tracing::error!("Unknown price source {}, fallback to ninja", other);
+            Box::new(NinjaSource::new(
+                config.ninja.currency.clone(),
+                config.ninja.item.clone(),
+            ))
+        }
+    }
+}
+
+// Parse a currencyoverview/itemoverview body into the unified response
+pub fn parse_response(category: Category, content: &str) -> Result<ApiResponse, String> {
+    let mut api_response = ApiResponse::empty();
+    if category.main_category() == MainCategory::Currency {
+        let res_json: QueryResponse =
+            serde_json::from_str(content).map_err(|e| format!("Parse data error: {}", e))?;
+        api_response.set_currency_response(res_json);
+    } else {
+        let res_json: ItemQueryResponse =
+            serde_json::from_str(content).map_err(|e| format!("Parse data error: {}", e))?;
+        api_response.set_item_response(res_json);
+    }
+    Ok(api_response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_source_currency() {
+        let source = FileSource::new("fixtures");
+        let res = source.fetch("Affliction", Category::Currency).await.unwrap();
+        let lines = res.currency_response.unwrap().lines;
+        assert!(lines.iter().any(|line| line.currencyTypeName == "Divine Orb"));
+        // ninja emits null for days without data
+        let primal = lines
+            .iter()
+            .find(|line| line.currencyTypeName == "Primal Crystallised Lifeforce")
+            .unwrap();
+        let data = &primal.paySparkLine.as_ref().unwrap().data;
+        assert_eq!(data.len(), 7);
+        assert_eq!(data[0], None);
+        assert_eq!(data[6], Some(9.52));
+    }
+
+    #[tokio::test]
+    async fn test_file_source_item() {
+        let source = FileSource::new("fixtures");
+        let res = source.fetch("Affliction", Category::Essence).await.unwrap();
+        assert!(res.currency_response.is_none());
+        assert!(!res.item_response.unwrap().lines.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_source_missing() {
+        let source = FileSource::new("fixtures");
+        let res = source.fetch("Affliction", Category::Beast).await;
+        assert!(res.is_err());
+    }
+}
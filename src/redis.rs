@@ -1,197 +1,323 @@
-use redis::{Client, Commands, Connection, RedisResult};
-use serde_derive::Deserialize;
+use async_trait::async_trait;
+use redis::{
+    aio::ConnectionManager, AsyncCommands, Client, ConnectionAddr, ConnectionInfo, ErrorKind,
+    RedisConnectionInfo, RedisResult, TlsCertificates,
+};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
 
-use crate::get_profile;
-use crate::init::{get_redis_config, Config};
+use crate::init::{read_config_file, RedisConfig};
+use crate::store::Store;
 
 
 // Define a struct to hold Redis
+// Clones share one connection manager, opened on first use and reconnected on failure
+#[derive(Clone)]
 pub struct RedisInstance {
-    pub connection: Connection,
-    init: bool,
-    pub profile: String,
-}
-
-impl Clone for RedisInstance {
-    fn clone(&self) -> RedisInstance {
-        RedisInstance::new(self.profile.as_str())
-    }
+    client: Client,
+    connection: Arc<OnceCell<ConnectionManager>>,
+    connect_timeout: Option<Duration>,
+    profile: String,
 }
 
 // Implement RedisInstance
 impl RedisInstance {
-    // new a RedisInstance struct and connect to Redis
+    // new a RedisInstance struct from the profile config
     pub fn new(profile: &str) -> RedisInstance {
-        let redis_config = get_redis_config(&profile);
-        let connection_str = format!(
-            "redis://{}:{}/{}",
-            redis_config.host, redis_config.port, redis_config.db
-        );
-        let connection = Client::open(connection_str.as_str())
-            .expect("Unable to open Redis connection")
-            .get_connection()
-            .expect("Unable to get Redis connection");
+        let redis_config = read_config_file(profile).redis;
+        RedisInstance::from_config(&redis_config, profile)
+    }
+
+    pub fn from_config(redis_config: &RedisConfig, profile: &str) -> RedisInstance {
+        let connection_info = get_connection_info(redis_config);
+        let client = match &redis_config.ca_cert {
+            Some(ca_cert) => {
+                // Never fall back to the system roots when a CA is pinned
+                let root_cert = std::fs::read(ca_cert)
+                    .unwrap_or_else(|e| panic!("Unable to read Redis CA certificate {}: {}", ca_cert, e));
+                let tls_certs = TlsCertificates { client_tls: None, root_cert: Some(root_cert) };
+                Client::build_with_tls(connection_info, tls_certs)
+            }
+            None => Client::open(connection_info),
+        }
+        .expect("Unable to open Redis connection");
         RedisInstance {
-            connection,
-            init: true,
+            client,
+            connection: Arc::new(OnceCell::new()),
+            connect_timeout: redis_config.connect_timeout_ms.map(Duration::from_millis),
             profile: String::from(profile),
         }
     }
 
-    // check if redis is initialized return a RedisResult
-    fn check_init(&self) -> RedisResult<bool> {
-        if self.init {
-            RedisResult::Ok(true)
-        } else {
-            Err(redis::RedisError::from((
-                redis::ErrorKind::InvalidClientConfig,
-                "Redis not initialized",
-            )))
-        }
+    // get the shared connection, connect if not yet connected
+    async fn connection(&self) -> RedisResult<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| async {
+                let connect = ConnectionManager::new(self.client.clone());
+                match self.connect_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, connect)
+                        .await
+                        .map_err(|_| redis::RedisError::from((ErrorKind::IoError, "Redis connect timeout")))?,
+                    None => connect.await,
+                }
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+}
+
+// Store backed by the Redis server of the profile
+#[async_trait]
+impl Store for RedisInstance {
+    fn profile(&self) -> &str {
+        &self.profile
     }
 
     // get a value from Redis
-    pub fn get(&mut self, key: &str) -> RedisResult<String> {
-        // check if Redis is initialized
-        self.check_init()?;
-        self.connection.get(key)
+    async fn get(&self, key: &str) -> RedisResult<String> {
+        let mut connection = self.connection().await?;
+        connection.get(key).await
     }
 
     // set a value to Redis
-    pub fn set(&mut self, key: &str, value: &str) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.set(key, value)
+    async fn set(&self, key: &str, value: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.set(key, value).await
     }
 
-    // set a value with expire to Redis
-    pub fn set_with_expire(&mut self, key: &str, value: &str, expire: u64) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.set_ex(key, value, expire)
+    // set a value with expire in milliseconds only if the key does not exist, return true if set
+    async fn set_nx_px(&self, key: &str, value: &str, expire_ms: u64) -> RedisResult<bool> {
+        let mut connection = self.connection().await?;
+        let res: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("NX")
+            .arg("PX")
+            .arg(expire_ms)
+            .query_async(&mut connection)
+            .await?;
+        Ok(res.is_some())
+    }
+
+    // increase a counter and return the new value
+    async fn incr(&self, key: &str) -> RedisResult<i64> {
+        let mut connection = self.connection().await?;
+        connection.incr(key, 1).await
     }
 
     // set expire to Redis
-    pub fn set_expire(&mut self, key: &str, expire: i64) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.expire(key, expire)
+    async fn set_expire(&self, key: &str, expire: i64) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.expire(key, expire).await
+    }
+
+    // remove expire from Redis
+    async fn persist(&self, key: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.persist(key).await
     }
 
     // delete a value from Redis
-    pub fn delete(&mut self, key: &str) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.del(key)
+    async fn delete(&self, key: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.del(key).await
     }
 
     // check if a key exists in Redis
-    pub fn exists(&mut self, key: &str) -> RedisResult<bool> {
-        self.check_init()?;
-        self.connection.exists(key)
+    async fn exists(&self, key: &str) -> RedisResult<bool> {
+        let mut connection = self.connection().await?;
+        connection.exists(key).await
     }
 
-    // set list to Redis
-    pub fn set_list(&mut self, key: &str, value: &Vec<String>) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.rpush(key, value)
+    // stage the list in a temp key and rename it over the old one in one MULTI/EXEC
+    async fn replace_list_expire(&self, key: &str, value: &[String], expire: i64) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        if value.is_empty() {
+            return connection.del(key).await;
+        }
+        let staging_key = format!("{}:staging:{}", key, uuid::Uuid::new_v4());
+        redis::pipe()
+            .atomic()
+            .rpush(&staging_key, value)
+            .ignore()
+            .expire(&staging_key, expire)
+            .ignore()
+            .rename(&staging_key, key)
+            .ignore()
+            .query_async(&mut connection)
+            .await
     }
 
-    // set list with expire to Redis
-    pub fn set_list_expire(
-        &mut self,
-        key: &str,
-        value: &Vec<String>,
-        expire: i64,
-    ) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.rpush(key, value)?;
-        self.connection.expire(key, expire)
+    // get list from Redis
+    async fn get_list(&self, key: &str) -> RedisResult<Vec<String>> {
+        let mut connection = self.connection().await?;
+        connection.lrange(key, 0, -1).await
     }
 
-    // Push element to list
-    pub fn push_list(&mut self, key: &str, value: &str) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.rpush(key, value)
+    // add member to set
+    async fn push_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.sadd(key, member).await
     }
 
-    // remove element from list
-    pub fn remove_list(&mut self, key: &str, value: &str) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.lrem(key, 0, value)
+    // remove member from set
+    async fn remove_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.srem(key, member).await
     }
 
-    // get list from Redis
-    pub fn get_list(&mut self, key: &str) -> RedisResult<Vec<String>> {
-        self.check_init()?;
-        self.connection.lrange(key, 0, -1)
-    }
-
-    // exist in list 
-    pub fn exist_in_list(&mut self, key: &str, value: &str) -> RedisResult<bool> {
-        self.check_init()?;
-        let list = self.connection.lrange(key, 0, -1);
-        let list:Vec<String> = match list {
-            Ok(list) => list,
-            Err(e) => {
-                tracing::error!("Redis execution error: {}", e);
-                return RedisResult::Ok(false);
-            }
-        };
-        for item in list.iter() {
-            if item == value {
-                return RedisResult::Ok(true);
-            }
+    // get all members of set
+    async fn get_set(&self, key: &str) -> RedisResult<HashSet<String>> {
+        let mut connection = self.connection().await?;
+        connection.smembers(key).await
+    }
+
+    // replace a set through a staged key renamed in MULTI/EXEC
+    async fn replace_set(&self, key: &str, members: &[String]) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        if members.is_empty() {
+            return connection.del(key).await;
         }
-        RedisResult::Ok(false)
+        let staging_key = format!("{}:staging:{}", key, uuid::Uuid::new_v4());
+        redis::pipe()
+            .atomic()
+            .sadd(&staging_key, members)
+            .ignore()
+            .rename(&staging_key, key)
+            .ignore()
+            .query_async(&mut connection)
+            .await
+    }
+
+    // check if member exists in set
+    async fn exist_in_set(&self, key: &str, member: &str) -> RedisResult<bool> {
+        let mut connection = self.connection().await?;
+        connection.sismember(key, member).await
+    }
+
+    // convert a list to a set in one script so readers never see it missing
+    async fn convert_list_to_set(&self, key: &str) -> RedisResult<bool> {
+        let mut connection = self.connection().await?;
+        let script = redis::Script::new(
+            r"
+            if redis.call('TYPE', KEYS[1]).ok ~= 'list' then return 0 end
+            local members = redis.call('LRANGE', KEYS[1], 0, -1)
+            local ttl = redis.call('PTTL', KEYS[1])
+            redis.call('DEL', KEYS[1])
+            for _, member in ipairs(members) do redis.call('SADD', KEYS[1], member) end
+            if ttl > 0 then redis.call('PEXPIRE', KEYS[1], ttl) end
+            return 1
+            ",
+        );
+        let converted: i64 = script.key(key).invoke_async(&mut connection).await?;
+        Ok(converted == 1)
     }
 
     // push hash to redis
-    pub fn push_hash_expire(&mut self, key: &str, field: &str, value: &str, expire: i64) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.hset(key, field, value)?;
-        self.connection.expire(key, expire)
+    async fn push_hash_expire(&self, key: &str, field: &str, value: &str, expire: i64) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.hset::<_, _, _, ()>(key, field, value).await?;
+        connection.expire(key, expire).await
+    }
+
+    // push hash to redis without expire
+    async fn push_hash(&self, key: &str, field: &str, value: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.hset(key, field, value).await
+    }
+
+    // set a hash field in one script only if it still holds the current value
+    async fn set_hash_if_equal(&self, key: &str, field: &str, current: &str, value: &str) -> RedisResult<bool> {
+        let mut connection = self.connection().await?;
+        let script = redis::Script::new(
+            r"if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then return redis.call('HSET', KEYS[1], ARGV[1], ARGV[3]) + 1 else return 0 end",
+        );
+        let set: i64 = script.key(key).arg(field).arg(current).arg(value).invoke_async(&mut connection).await?;
+        Ok(set > 0)
+    }
+
+    // get all fields and values of hash from redis
+    async fn get_all_hash(&self, key: &str) -> RedisResult<Vec<(String, String)>> {
+        let mut connection = self.connection().await?;
+        connection.hgetall(key).await
     }
 
     // get hash from redis
-    pub fn get_hash(&mut self, key: &str, field: &str) -> RedisResult<String> {
-        self.check_init()?;
-        self.connection.hget(key, field)
+    async fn get_hash(&self, key: &str, field: &str) -> RedisResult<String> {
+        let mut connection = self.connection().await?;
+        connection.hget(key, field).await
     }
 
     // remove hash from redis
-    pub fn remove_hash(&mut self, key: &str, field: &str) -> RedisResult<()> {
-        self.check_init()?;
-        self.connection.hdel(key, field)
+    async fn remove_hash(&self, key: &str, field: &str) -> RedisResult<()> {
+        let mut connection = self.connection().await?;
+        connection.hdel(key, field).await
+    }
+
+    // add the members and trim every sorted set in one pipeline
+    async fn push_sorted_sets(&self, members: &[(String, String)], score: i64, min: i64) -> RedisResult<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let mut connection = self.connection().await?;
+        let mut pipe = redis::pipe();
+        for (key, member) in members {
+            pipe.zadd(key, member, score).ignore().zrembyscore(key, i64::MIN, min - 1).ignore();
+        }
+        pipe.query_async(&mut connection).await
+    }
+
+    // get members with score between min and max from sorted set
+    async fn get_sorted_set_by_score(&self, key: &str, min: i64, max: i64) -> RedisResult<Vec<(String, i64)>> {
+        let mut connection = self.connection().await?;
+        connection.zrangebyscore_withscores(key, min, max).await
     }
 
     // get all exists keys in specific layer from redis
-    pub fn get_all_keys(&mut self, key: &str) -> RedisResult<Vec<String>> {
-        self.check_init()?;
-        self.connection.keys(key)
-    }
-
-    // get all exists keys name in specific layer from redis
-    pub fn get_all_keys_name(&mut self, key: &str) -> RedisResult<Vec<String>> {
-        self.check_init()?;
-        let keys: Vec<String> = self.connection.keys(key)?;
-        let mut keys_name: Vec<String> = Vec::new();
-        // calculate the number of layers in key
-        let key_layer = key.split(":").collect::<Vec<&str>>().len();
-        for key in keys.iter() {
-            let key = key.split(":").collect::<Vec<&str>>();
-            if key.len() < key_layer {
-                continue;
-            }
-            let key = key[key_layer - 1];
-            keys_name.push(String::from(key));
+    // SCAN instead of KEYS so a large keyspace does not block the server, a key may be returned twice
+    async fn get_all_keys(&self, key: &str) -> RedisResult<Vec<String>> {
+        let mut connection = self.connection().await?;
+        let mut iter = connection.scan_match::<_, String>(key).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
         }
-        RedisResult::Ok(keys_name)
+        Ok(keys)
+    }
+}
+
+// Build the connection info from the config, rediss:// when tls is set
+fn get_connection_info(redis_config: &RedisConfig) -> ConnectionInfo {
+    let addr = if redis_config.tls || redis_config.ca_cert.is_some() {
+        ConnectionAddr::TcpTls {
+            host: redis_config.host.clone(),
+            port: redis_config.port,
+            insecure: false,
+            tls_params: None,
+        }
+    } else {
+        ConnectionAddr::Tcp(redis_config.host.clone(), redis_config.port)
+    };
+    ConnectionInfo {
+        addr,
+        redis: RedisConnectionInfo {
+            db: redis_config.db as i64,
+            username: redis_config.username.clone(),
+            password: redis_config.password.clone(),
+        },
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
-    fn test_redis() {
-        let mut redis_instance = RedisInstance::new("local");
+    #[tokio::test]
+    #[ignore = "needs a Redis server and Config_local.toml"]
+    async fn test_redis() {
+        let redis_instance = RedisInstance::new("local");
         let key = "test";
         let value = "test";
         // let res = redis_instance.set(key, value);
@@ -203,19 +329,55 @@ mod tests {
         // assert_eq!(res.is_ok(), true);
         // let res = redis_instance.get(key);
         // assert_eq!(res.is_err(), true);
-        // let res = redis_instance.set_list(key, &vec![String::from("test1"), String::from("test2")]);
-        // assert_eq!(res.is_ok(), true);
-        let res = redis_instance.set_list_expire(
+        let res = redis_instance.replace_list_expire(
             key,
             &vec![String::from("test1"), String::from("test2")],
             60,
-        );
+        ).await;
         assert_eq!(res.is_ok(), true);
-        let res = redis_instance.get_list(key);
+        let res = redis_instance.get_list(key).await;
         println!("{:?}", res.ok());
-        let res = redis_instance.get_all_keys("Currency:*");
+        let res = redis_instance.get_all_keys("Currency:*").await;
         println!("{:?}", res.ok());
-        let res = redis_instance.get_all_keys_name("Data:*");
+        let res = redis_instance.get_all_keys_name("Data:*").await;
         println!("{:?}", res.ok());
     }
+
+    #[test]
+    fn test_get_connection_info() {
+        let mut redis_config = RedisConfig {
+            host: String::from("redis.internal"),
+            port: 6380,
+            db: 2,
+            password: Some(String::from("secret")),
+            username: Some(String::from("notify")),
+            tls: false,
+            ca_cert: None,
+            connect_timeout_ms: Some(3000),
+        };
+        let connection_info = get_connection_info(&redis_config);
+        assert_eq!(connection_info.addr, ConnectionAddr::Tcp(String::from("redis.internal"), 6380));
+        assert_eq!(connection_info.redis.db, 2);
+        assert_eq!(connection_info.redis.username.as_deref(), Some("notify"));
+        assert_eq!(connection_info.redis.password.as_deref(), Some("secret"));
+        redis_config.tls = true;
+        let connection_info = get_connection_info(&redis_config);
+        assert!(matches!(connection_info.addr, ConnectionAddr::TcpTls { insecure: false, .. }));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to read Redis CA certificate missing-ca.pem")]
+    fn test_missing_ca_cert() {
+        let redis_config = RedisConfig {
+            host: String::from("redis.internal"),
+            port: 6380,
+            db: 0,
+            password: None,
+            username: None,
+            tls: true,
+            ca_cert: Some(String::from("missing-ca.pem")),
+            connect_timeout_ms: None,
+        };
+        RedisInstance::from_config(&redis_config, "local");
+    }
 }
@@ -0,0 +1,258 @@
+use axum::{extract::{Path, State}, http::StatusCode, Json};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde_json::{json, Value};
+use tokio_cron_scheduler::Job;
+use uuid::Uuid;
+
+use crate::discord::send_message_to_channel;
+use crate::job_handler::{run_report, set_job_id};
+use crate::models::{Reminder, ReminderRequest, ScheduleConfig};
+use crate::ninja_handler::{bad_request, internal_error, parse_category};
+use crate::store::{SharedStore, Store};
+use crate::AppState;
+
+// Redis hash of the pending reminders, reminder id to Reminder
+const REMINDER_KEY: &str = "Reminders";
+
+// Public functions
+// List the pending reminders
+pub async fn list_reminders(State(state): State<AppState>) -> Result<(StatusCode, Json<Vec<Reminder>>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let reminders = get_all_reminders(store).await.map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(reminders)))
+}
+
+// Get a pending reminder
+pub async fn get_reminder(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<Reminder>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let reminder = find_reminder(&id, store).await?;
+    Ok((StatusCode::OK, Json(reminder)))
+}
+
+// Schedule a one-off notification
+pub async fn create_reminder(
+    State(state): State<AppState>,
+    Json(payload): Json<ReminderRequest>,
+) -> Result<(StatusCode, Json<Reminder>), (StatusCode, Json<Value>)> {
+    let mut categories = Vec::new();
+    for category in payload.categories.iter() {
+        categories.push(parse_category(category)?.to_string());
+    }
+    if payload.message.is_none() && categories.is_empty() {
+        return Err(bad_request(String::from("message or categories is required")));
+    }
+    let fire_at = resolve_fire_at(&payload, Utc::now()).map_err(bad_request)?;
+    let reminder = Reminder {
+        id: Uuid::new_v4().to_string(),
+        message: payload.message,
+        categories,
+        fire_at: fire_at.to_rfc3339(),
+        created_at: Utc::now().to_string(),
+    };
+    let job = build_reminder_job(&reminder, state.store.clone()).map_err(bad_request)?;
+    // Persist before scheduling so a reminder that fires can always be listed and cancelled
+    let store = state.store.as_ref();
+    store.push_hash(REMINDER_KEY, &reminder.id, &reminder.to_json_string()).await.map_err(internal_error)?;
+    if let Err(e) = state.scheduler.add(job).await {
+        tracing::error!("Reminder {} failed to add: {}", reminder.id, e);
+        if let Err(e) = store.remove_hash(REMINDER_KEY, &reminder.id).await {
+            tracing::error!("Reminder {} failed to roll back: {}", reminder.id, e);
+        }
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Reminder failed to add" }))));
+    }
+    if let Err(e) = state.scheduler.start().await {
+        tracing::debug!("Scheduler not started: {}", e);
+    }
+    tracing::info!("Reminder {} added at {}", reminder.id, reminder.fire_at);
+    Ok((StatusCode::CREATED, Json(reminder)))
+}
+
+// Cancel a pending reminder
+pub async fn delete_reminder(State(state): State<AppState>, Path(id): Path<String>) -> Result<(StatusCode, Json<Reminder>), (StatusCode, Json<Value>)> {
+    let store = state.store.as_ref();
+    let reminder = find_reminder(&id, store).await?;
+    if let Ok(uuid) = Uuid::parse_str(&id) {
+        if let Err(e) = state.scheduler.remove(&uuid).await {
+            tracing::error!("Reminder {} failed to remove: {}", id, e);
+        }
+    }
+    store.remove_hash(REMINDER_KEY, &id).await.map_err(internal_error)?;
+    tracing::info!("Reminder {} cancelled", id);
+    Ok((StatusCode::OK, Json(reminder)))
+}
+
+// Re-register the pending reminders, used at startup, return the number restored
+// A reminder due while the process was down fires right away
+pub async fn restore_reminders(state: &AppState) -> usize {
+    let store = state.store.as_ref();
+    let reminders = match get_all_reminders(store).await {
+        Ok(reminders) => reminders,
+        Err(e) => {
+            tracing::error!("Get reminders error: {}", e);
+            return 0;
+        }
+    };
+    let mut restored = 0;
+    for reminder in reminders {
+        let job = match build_reminder_job(&reminder, state.store.clone()) {
+            Ok(job) => job,
+            Err(e) => {
+                tracing::error!("Reminder {} failed to build: {}", reminder.id, e);
+                continue;
+            }
+        };
+        match state.scheduler.add(job).await {
+            Ok(_) => {
+                tracing::info!("Reminder {} restored at {}", reminder.id, reminder.fire_at);
+                restored += 1;
+            }
+            Err(e) => tracing::error!("Reminder {} failed to restore: {}", reminder.id, e),
+        }
+    }
+    restored
+}
+
+// Private functions
+// Resolve when a reminder fires, HH:MM is the next occurrence in the timezone
+fn resolve_fire_at(payload: &ReminderRequest, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if let Some(delay_secs) = payload.delay_secs {
+        if delay_secs <= 0 {
+            return Err(String::from("delay_secs must be positive"));
+        }
+        return Ok(now + Duration::seconds(delay_secs));
+    }
+    let at = payload.at.as_ref().ok_or(String::from("at or delay_secs is required"))?;
+    if let Ok(fire_at) = DateTime::parse_from_rfc3339(at) {
+        let fire_at = fire_at.with_timezone(&Utc);
+        if fire_at <= now {
+            return Err(format!("{} is in the past", at));
+        }
+        return Ok(fire_at);
+    }
+    let time = NaiveTime::parse_from_str(at, "%H:%M").map_err(|_| format!("Invalid time {}: RFC 3339 or HH:MM expected", at))?;
+    let timezone: Tz = payload
+        .timezone
+        .parse()
+        .map_err(|e| format!("Invalid timezone {}: {}", payload.timezone, e))?;
+    let today = now.with_timezone(&timezone).date_naive();
+    for date in [today, today + Duration::days(1)] {
+        if let Some(fire_at) = timezone.from_local_datetime(&date.and_time(time)).earliest() {
+            let fire_at = fire_at.with_timezone(&Utc);
+            if fire_at > now {
+                return Ok(fire_at);
+            }
+        }
+    }
+    Err(format!("{} does not exist in {}", at, payload.timezone))
+}
+
+fn build_reminder_job(reminder: &Reminder, store: SharedStore) -> Result<Job, String> {
+    let fire_at = DateTime::parse_from_rfc3339(&reminder.fire_at)
+        .map_err(|e| format!("Invalid fire time {}: {}", reminder.fire_at, e))?;
+    let delay = (fire_at.with_timezone(&Utc) - Utc::now())
+        .to_std()
+        .unwrap_or_default()
+        .max(std::time::Duration::from_secs(1));
+    let id = Uuid::parse_str(&reminder.id).map_err(|e| format!("Invalid uuid {}: {}", reminder.id, e))?;
+    let reminder = reminder.clone();
+    let mut job = Job::new_one_shot_async(delay, move |_uuid, _l| Box::pin(fire_reminder(reminder.clone(), store.clone())))
+        .map_err(|e| format!("Build reminder error: {}", e))?;
+    set_job_id(&mut job, id)?;
+    Ok(job)
+}
+
+// Send the message and the reports, then drop the reminder
+async fn fire_reminder(reminder: Reminder, store: SharedStore) {
+    tracing::info!("Reminder {} fired", reminder.id);
+    if let Some(message) = &reminder.message {
+        if let Err(e) = send_message_to_channel(format!("# Reminder\n- {}\n", message)).await {
+            tracing::error!("Reminder {} send error: {}", reminder.id, e);
+        }
+    }
+    if !reminder.categories.is_empty() {
+        let schedule = ScheduleConfig { categories: reminder.categories.clone(), ..ScheduleConfig::default() };
+        match schedule.get_category_map() {
+            Ok(categories) => {
+                match run_report(categories, &store).await {
+                    Ok(report) if !report.errors.is_empty() => {
+                        tracing::error!("Reminder {} report errors: {}", reminder.id, report.errors.join("; "));
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("Reminder {} report error: {}", reminder.id, e),
+                }
+            }
+            Err(e) => tracing::error!("Reminder {} invalid categories: {}", reminder.id, e),
+        }
+    }
+    let _ = store.remove_hash(REMINDER_KEY, &reminder.id).await;
+}
+
+async fn get_all_reminders(store: &dyn Store) -> redis::RedisResult<Vec<Reminder>> {
+    let mut reminders = Vec::new();
+    for (id, value) in store.get_all_hash(REMINDER_KEY).await? {
+        match serde_json::from_str::<Reminder>(&value) {
+            Ok(reminder) => reminders.push(reminder),
+            Err(e) => tracing::error!("Parse reminder {} error: {}", id, e),
+        }
+    }
+    Ok(reminders)
+}
+
+async fn find_reminder(id: &str, store: &dyn Store) -> Result<Reminder, (StatusCode, Json<Value>)> {
+    let value = match store.get_hash(REMINDER_KEY, id).await {
+        Ok(value) => value,
+        // a missing field is a type error in every store, like HGET of nil in Redis
+        Err(e) if e.kind() == redis::ErrorKind::TypeError => {
+            return Err((StatusCode::NOT_FOUND, Json(json!({ "error": format!("Reminder {} not found", id) }))));
+        }
+        Err(e) => return Err(internal_error(e)),
+    };
+    serde_json::from_str(&value).map_err(|e| {
+        tracing::error!("Parse reminder {} error: {}", id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Reminder {} is corrupted", id) })))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_fire_at() {
+        let now = DateTime::parse_from_rfc3339("2024-01-10T13:00:00Z").unwrap().with_timezone(&Utc);
+        let mut payload = ReminderRequest {
+            message: Some(String::from("check Scarabs")),
+            categories: Vec::new(),
+            at: None,
+            delay_secs: Some(1800),
+            timezone: String::from("Asia/Taipei"),
+        };
+        assert_eq!(resolve_fire_at(&payload, now).unwrap().to_rfc3339(), "2024-01-10T13:30:00+00:00");
+        payload.delay_secs = None;
+        // 20:00 in Taipei has passed, so it is tomorrow
+        payload.at = Some(String::from("20:00"));
+        assert_eq!(resolve_fire_at(&payload, now).unwrap().to_rfc3339(), "2024-01-11T12:00:00+00:00");
+        payload.at = Some(String::from("22:30"));
+        assert_eq!(resolve_fire_at(&payload, now).unwrap().to_rfc3339(), "2024-01-10T14:30:00+00:00");
+        payload.at = Some(String::from("2024-01-10T12:00:00Z"));
+        assert!(resolve_fire_at(&payload, now).is_err());
+        payload.at = Some(String::from("tonight"));
+        assert!(resolve_fire_at(&payload, now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_find_reminder() {
+        let store = crate::store::MemoryStore::new("local");
+        let reminder = Reminder {
+            id: Uuid::new_v4().to_string(),
+            message: Some(String::from("check Scarabs")),
+            categories: Vec::new(),
+            fire_at: String::from("2024-01-10T13:30:00+00:00"),
+            created_at: String::new(),
+        };
+        store.push_hash(REMINDER_KEY, &reminder.id, &reminder.to_json_string()).await.unwrap();
+        assert_eq!(find_reminder(&reminder.id, &store).await.unwrap().message, reminder.message);
+        assert_eq!(find_reminder("missing", &store).await.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,529 @@
+use async_trait::async_trait;
+use redis::{ErrorKind, RedisError, RedisResult};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::store::{not_found, wrong_type, Store};
+
+// Schema changes in order, user_version holds the number applied
+// Every key has a row in entries with its kind and expiry, collections keep their members in their own table
+const MIGRATIONS: &[&str] = &[r"
+    CREATE TABLE entries (key TEXT PRIMARY KEY, kind TEXT NOT NULL, value TEXT, expire_at INTEGER);
+    CREATE TABLE list_items (key TEXT NOT NULL, pos INTEGER NOT NULL, value TEXT NOT NULL, PRIMARY KEY (key, pos));
+    CREATE TABLE hash_fields (key TEXT NOT NULL, field TEXT NOT NULL, value TEXT NOT NULL, PRIMARY KEY (key, field));
+    CREATE TABLE sorted_set_members (key TEXT NOT NULL, member TEXT NOT NULL, score INTEGER NOT NULL, PRIMARY KEY (key, member));
+    CREATE INDEX sorted_set_members_score ON sorted_set_members (key, score);
+", r"
+    CREATE TABLE set_members (key TEXT NOT NULL, member TEXT NOT NULL, PRIMARY KEY (key, member));
+", r"
+    CREATE INDEX entries_expire_at ON entries (expire_at);
+"];
+
+const MEMBER_TABLES: [&str; 4] = ["list_items", "hash_fields", "set_members", "sorted_set_members"];
+
+// Store kept in a local SQLite file, for single host deployments
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+    profile: String,
+}
+
+enum SqliteError {
+    Sql(rusqlite::Error),
+    Store(RedisError),
+}
+
+impl From<rusqlite::Error> for SqliteError {
+    fn from(e: rusqlite::Error) -> SqliteError {
+        SqliteError::Sql(e)
+    }
+}
+
+impl From<SqliteError> for RedisError {
+    fn from(e: SqliteError) -> RedisError {
+        match e {
+            SqliteError::Sql(e) => RedisError::from((ErrorKind::IoError, "SQLite error", e.to_string())),
+            SqliteError::Store(e) => e,
+        }
+    }
+}
+
+type SqliteResult<T> = Result<T, SqliteError>;
+
+impl SqliteStore {
+    // open the database at path, created and migrated if needed
+    pub fn new(path: &str, profile: &str) -> SqliteStore {
+        let mut connection = Connection::open(path).expect("Unable to open SQLite database");
+        migrate(&mut connection).expect("Unable to migrate SQLite database");
+        SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+            profile: String::from(profile),
+        }
+    }
+
+    // run f in a transaction on the blocking pool, the expired keys are dropped first
+    async fn call<T, F>(&self, f: F) -> RedisResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction) -> SqliteResult<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        let res = tokio::task::spawn_blocking(move || -> SqliteResult<T> {
+            let mut connection = connection.lock().unwrap();
+            let tx = connection.transaction()?;
+            purge_expired(&tx)?;
+            let res = f(&tx)?;
+            tx.commit()?;
+            Ok(res)
+        })
+        .await
+        .map_err(|e| RedisError::from((ErrorKind::IoError, "SQLite task error", e.to_string())))?;
+        res.map_err(RedisError::from)
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    async fn get(&self, key: &str) -> RedisResult<String> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            let entry: Option<(String, Option<String>)> = tx
+                .query_row("SELECT kind, value FROM entries WHERE key = ?1", [&key], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?;
+            match entry {
+                Some((kind, Some(value))) if kind == "string" => Ok(value),
+                Some(_) => Err(SqliteError::Store(wrong_type())),
+                None => Err(SqliteError::Store(not_found(&key))),
+            }
+        })
+        .await
+    }
+
+    async fn set(&self, key: &str, value: &str) -> RedisResult<()> {
+        let (key, value) = (String::from(key), String::from(value));
+        self.call(move |tx| set_string(tx, &key, &value, None)).await
+    }
+
+    async fn set_nx_px(&self, key: &str, value: &str, expire_ms: u64) -> RedisResult<bool> {
+        let (key, value) = (String::from(key), String::from(value));
+        self.call(move |tx| {
+            if get_kind(tx, &key)?.is_some() {
+                return Ok(false);
+            }
+            set_string(tx, &key, &value, Some(now_ms() + expire_ms as i64))?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn incr(&self, key: &str) -> RedisResult<i64> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            let value: Option<(String, Option<String>)> = tx
+                .query_row("SELECT kind, value FROM entries WHERE key = ?1", [&key], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?;
+            let counter = match value {
+                Some((kind, Some(value))) if kind == "string" => value.parse::<i64>().map_err(|_| SqliteError::Store(wrong_type()))?,
+                Some(_) => return Err(SqliteError::Store(wrong_type())),
+                None => 0,
+            } + 1;
+            tx.execute(
+                "INSERT INTO entries (key, kind, value) VALUES (?1, 'string', ?2) ON CONFLICT (key) DO UPDATE SET value = ?2",
+                params![key, counter.to_string()],
+            )?;
+            Ok(counter)
+        })
+        .await
+    }
+
+    async fn set_expire(&self, key: &str, expire: i64) -> RedisResult<()> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            if expire <= 0 {
+                delete_key(tx, &key)?;
+            } else {
+                tx.execute("UPDATE entries SET expire_at = ?2 WHERE key = ?1", params![key, now_ms() + expire * 1000])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn persist(&self, key: &str) -> RedisResult<()> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            tx.execute("UPDATE entries SET expire_at = NULL WHERE key = ?1", [&key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> RedisResult<()> {
+        let key = String::from(key);
+        self.call(move |tx| delete_key(tx, &key)).await
+    }
+
+    async fn exists(&self, key: &str) -> RedisResult<bool> {
+        let key = String::from(key);
+        self.call(move |tx| Ok(get_kind(tx, &key)?.is_some())).await
+    }
+
+    async fn replace_list_expire(&self, key: &str, value: &[String], expire: i64) -> RedisResult<()> {
+        let (key, value) = (String::from(key), value.to_vec());
+        self.call(move |tx| {
+            delete_key(tx, &key)?;
+            if value.is_empty() || expire <= 0 {
+                return Ok(());
+            }
+            push_list(tx, &key, &value)?;
+            tx.execute("UPDATE entries SET expire_at = ?2 WHERE key = ?1", params![key, now_ms() + expire * 1000])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_list(&self, key: &str) -> RedisResult<Vec<String>> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "list")? {
+                return Ok(Vec::new());
+            }
+            let mut statement = tx.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY pos")?;
+            let list = statement.query_map([&key], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(list)
+        })
+        .await
+    }
+
+    async fn push_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        let (key, member) = (String::from(key), String::from(member));
+        self.call(move |tx| {
+            create_kind(tx, &key, "set")?;
+            tx.execute("INSERT OR IGNORE INTO set_members (key, member) VALUES (?1, ?2)", [&key, &member])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn replace_set(&self, key: &str, members: &[String]) -> RedisResult<()> {
+        let (key, members) = (String::from(key), members.to_vec());
+        self.call(move |tx| {
+            delete_key(tx, &key)?;
+            if members.is_empty() {
+                return Ok(());
+            }
+            create_kind(tx, &key, "set")?;
+            for member in members {
+                tx.execute("INSERT OR IGNORE INTO set_members (key, member) VALUES (?1, ?2)", [&key, &member])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        let (key, member) = (String::from(key), String::from(member));
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "set")? {
+                return Ok(());
+            }
+            tx.execute("DELETE FROM set_members WHERE key = ?1 AND member = ?2", [&key, &member])?;
+            drop_if_empty(tx, &key, "set_members")
+        })
+        .await
+    }
+
+    async fn get_set(&self, key: &str) -> RedisResult<HashSet<String>> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "set")? {
+                return Ok(HashSet::new());
+            }
+            let mut statement = tx.prepare("SELECT member FROM set_members WHERE key = ?1")?;
+            let set = statement.query_map([&key], |row| row.get(0))?.collect::<rusqlite::Result<HashSet<String>>>()?;
+            Ok(set)
+        })
+        .await
+    }
+
+    async fn exist_in_set(&self, key: &str, member: &str) -> RedisResult<bool> {
+        let (key, member) = (String::from(key), String::from(member));
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "set")? {
+                return Ok(false);
+            }
+            let exists = tx
+                .query_row("SELECT 1 FROM set_members WHERE key = ?1 AND member = ?2", [&key, &member], |_| Ok(()))
+                .optional()?;
+            Ok(exists.is_some())
+        })
+        .await
+    }
+
+    async fn convert_list_to_set(&self, key: &str) -> RedisResult<bool> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            if get_kind(tx, &key)?.as_deref() != Some("list") {
+                return Ok(false);
+            }
+            tx.execute("INSERT OR IGNORE INTO set_members (key, member) SELECT key, value FROM list_items WHERE key = ?1", [&key])?;
+            tx.execute("DELETE FROM list_items WHERE key = ?1", [&key])?;
+            tx.execute("UPDATE entries SET kind = 'set' WHERE key = ?1", [&key])?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn push_hash_expire(&self, key: &str, field: &str, value: &str, expire: i64) -> RedisResult<()> {
+        self.push_hash(key, field, value).await?;
+        self.set_expire(key, expire).await
+    }
+
+    async fn push_hash(&self, key: &str, field: &str, value: &str) -> RedisResult<()> {
+        let (key, field, value) = (String::from(key), String::from(field), String::from(value));
+        self.call(move |tx| {
+            create_kind(tx, &key, "hash")?;
+            tx.execute(
+                "INSERT INTO hash_fields (key, field, value) VALUES (?1, ?2, ?3) ON CONFLICT (key, field) DO UPDATE SET value = ?3",
+                [&key, &field, &value],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_hash_if_equal(&self, key: &str, field: &str, current: &str, value: &str) -> RedisResult<bool> {
+        let (key, field, current, value) = (String::from(key), String::from(field), String::from(current), String::from(value));
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "hash")? {
+                return Ok(false);
+            }
+            let set = tx.execute(
+                "UPDATE hash_fields SET value = ?4 WHERE key = ?1 AND field = ?2 AND value = ?3",
+                [&key, &field, &current, &value],
+            )?;
+            Ok(set == 1)
+        })
+        .await
+    }
+
+    async fn get_all_hash(&self, key: &str) -> RedisResult<Vec<(String, String)>> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "hash")? {
+                return Ok(Vec::new());
+            }
+            let mut statement = tx.prepare("SELECT field, value FROM hash_fields WHERE key = ?1")?;
+            let hash = statement
+                .query_map([&key], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+            Ok(hash)
+        })
+        .await
+    }
+
+    async fn get_hash(&self, key: &str, field: &str) -> RedisResult<String> {
+        let (key, field) = (String::from(key), String::from(field));
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "hash")? {
+                return Err(SqliteError::Store(not_found(&key)));
+            }
+            let value: Option<String> = tx
+                .query_row("SELECT value FROM hash_fields WHERE key = ?1 AND field = ?2", [&key, &field], |row| row.get(0))
+                .optional()?;
+            value.ok_or_else(|| SqliteError::Store(not_found(&key)))
+        })
+        .await
+    }
+
+    async fn remove_hash(&self, key: &str, field: &str) -> RedisResult<()> {
+        let (key, field) = (String::from(key), String::from(field));
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "hash")? {
+                return Ok(());
+            }
+            tx.execute("DELETE FROM hash_fields WHERE key = ?1 AND field = ?2", [&key, &field])?;
+            drop_if_empty(tx, &key, "hash_fields")
+        })
+        .await
+    }
+
+    // one transaction for the whole batch
+    async fn push_sorted_sets(&self, members: &[(String, String)], score: i64, min: i64) -> RedisResult<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let members = members.to_vec();
+        self.call(move |tx| {
+            for (key, member) in members.iter() {
+                create_kind(tx, key, "zset")?;
+                tx.prepare_cached(
+                    "INSERT INTO sorted_set_members (key, member, score) VALUES (?1, ?2, ?3) ON CONFLICT (key, member) DO UPDATE SET score = ?3",
+                )?
+                .execute(params![key, member, score])?;
+                tx.prepare_cached("DELETE FROM sorted_set_members WHERE key = ?1 AND score < ?2")?
+                    .execute(params![key, min])?;
+                drop_if_empty(tx, key, "sorted_set_members")?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_sorted_set_by_score(&self, key: &str, min: i64, max: i64) -> RedisResult<Vec<(String, i64)>> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            if !check_kind(tx, &key, "zset")? {
+                return Ok(Vec::new());
+            }
+            let mut statement = tx.prepare(
+                "SELECT member, score FROM sorted_set_members WHERE key = ?1 AND score BETWEEN ?2 AND ?3 ORDER BY score, member",
+            )?;
+            let members = statement
+                .query_map(params![key, min, max], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, i64)>>>()?;
+            Ok(members)
+        })
+        .await
+    }
+
+    // GLOB follows the Redis pattern syntax
+    async fn get_all_keys(&self, key: &str) -> RedisResult<Vec<String>> {
+        let key = String::from(key);
+        self.call(move |tx| {
+            let mut statement = tx.prepare("SELECT key FROM entries WHERE key GLOB ?1")?;
+            let keys = statement.query_map([&key], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(keys)
+        })
+        .await
+    }
+}
+
+// Apply the migrations the database has not seen yet
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        tracing::info!("SQLite migration {} applied", index + 1);
+    }
+    Ok(())
+}
+
+fn now_ms() -> i64 {
+    chrono::offset::Utc::now().timestamp_millis()
+}
+
+fn purge_expired(tx: &Transaction) -> rusqlite::Result<()> {
+    let now = now_ms();
+    for table in MEMBER_TABLES {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE key IN (SELECT key FROM entries WHERE expire_at <= ?1)", table),
+            [now],
+        )?;
+    }
+    tx.execute("DELETE FROM entries WHERE expire_at <= ?1", [now])?;
+    Ok(())
+}
+
+fn delete_key(tx: &Transaction, key: &str) -> SqliteResult<()> {
+    for table in MEMBER_TABLES {
+        tx.execute(&format!("DELETE FROM {} WHERE key = ?1", table), [key])?;
+    }
+    tx.execute("DELETE FROM entries WHERE key = ?1", [key])?;
+    Ok(())
+}
+
+fn get_kind(tx: &Transaction, key: &str) -> SqliteResult<Option<String>> {
+    let kind = tx.query_row("SELECT kind FROM entries WHERE key = ?1", [key], |row| row.get(0)).optional()?;
+    Ok(kind)
+}
+
+// true if the key holds the kind, false if missing, an error if it holds another kind
+fn check_kind(tx: &Transaction, key: &str, kind: &str) -> SqliteResult<bool> {
+    match get_kind(tx, key)? {
+        Some(current) if current == kind => Ok(true),
+        Some(_) => Err(SqliteError::Store(wrong_type())),
+        None => Ok(false),
+    }
+}
+
+// create the key with the kind if missing
+fn create_kind(tx: &Transaction, key: &str, kind: &str) -> SqliteResult<()> {
+    if !check_kind(tx, key, kind)? {
+        tx.execute("INSERT INTO entries (key, kind) VALUES (?1, ?2)", [key, kind])?;
+    }
+    Ok(())
+}
+
+// drop a collection left without members, like Redis does
+fn drop_if_empty(tx: &Transaction, key: &str, table: &str) -> SqliteResult<()> {
+    tx.execute(
+        &format!("DELETE FROM entries WHERE key = ?1 AND NOT EXISTS (SELECT 1 FROM {} WHERE key = ?1)", table),
+        [key],
+    )?;
+    Ok(())
+}
+
+fn set_string(tx: &Transaction, key: &str, value: &str, expire_at: Option<i64>) -> SqliteResult<()> {
+    delete_key(tx, key)?;
+    tx.execute(
+        "INSERT INTO entries (key, kind, value, expire_at) VALUES (?1, 'string', ?2, ?3)",
+        params![key, value, expire_at],
+    )?;
+    Ok(())
+}
+
+fn push_list(tx: &Transaction, key: &str, values: &[String]) -> SqliteResult<()> {
+    create_kind(tx, key, "list")?;
+    for value in values {
+        tx.execute(
+            "INSERT INTO list_items (key, pos, value) SELECT ?1, COALESCE(MAX(pos), 0) + 1, ?2 FROM list_items WHERE key = ?1",
+            [key, value],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::check_store;
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        check_store(&SqliteStore::new(":memory:", "local")).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_reopen() {
+        let path = std::env::temp_dir().join(format!("notify-schedule-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let store = SqliteStore::new(path, "local");
+        store.push_hash("Alerts", "1", "{}").await.unwrap();
+        store.replace_list_expire("Data:Currency:Currency", &[String::from("{}")], 3600).await.unwrap();
+        drop(store);
+        // migrations are not applied twice and the data is kept
+        let store = SqliteStore::new(path, "local");
+        assert_eq!(store.get_hash("Alerts", "1").await.unwrap(), "{}");
+        assert!(store.exists("Data:Currency:Currency").await.unwrap());
+        let version: usize = store.connection.lock().unwrap().query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let plan: String = store
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("EXPLAIN QUERY PLAN SELECT key FROM entries WHERE expire_at <= 0", [], |row| row.get(3))
+            .unwrap();
+        assert!(plan.contains("entries_expire_at"), "{}", plan);
+        drop(store);
+        let _ = std::fs::remove_file(path);
+    }
+}
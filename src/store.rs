@@ -0,0 +1,646 @@
+use async_trait::async_trait;
+use redis::{ErrorKind, RedisError, RedisResult};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::init::read_config_file;
+use crate::redis::RedisInstance;
+use crate::sqlite::SqliteStore;
+use crate::util::glob_match;
+
+// Key value storage behind the handlers, shared by every clone of the app state
+pub type SharedStore = Arc<dyn Store>;
+
+// The operations the handlers use, named after the Redis commands they map to
+// Errors are RedisError for every backend so callers handle them the same way
+#[async_trait]
+pub trait Store: Send + Sync {
+    // profile the store was opened with, used to read the other config sections
+    fn profile(&self) -> &str;
+
+    // the cache of a split store, where a redis store used before left the durable keys
+    fn cache(&self) -> Option<&dyn Store> {
+        None
+    }
+
+    async fn get(&self, key: &str) -> RedisResult<String>;
+    async fn set(&self, key: &str, value: &str) -> RedisResult<()>;
+    // set only if the key does not exist, return true if set
+    async fn set_nx_px(&self, key: &str, value: &str, expire_ms: u64) -> RedisResult<bool>;
+    async fn incr(&self, key: &str) -> RedisResult<i64>;
+    async fn set_expire(&self, key: &str, expire: i64) -> RedisResult<()>;
+    // remove the expiry of a key
+    async fn persist(&self, key: &str) -> RedisResult<()>;
+    async fn delete(&self, key: &str) -> RedisResult<()>;
+    async fn exists(&self, key: &str) -> RedisResult<bool>;
+
+    // replace the whole list, readers see the old or the new list but never a part of it
+    async fn replace_list_expire(&self, key: &str, value: &[String], expire: i64) -> RedisResult<()>;
+    async fn get_list(&self, key: &str) -> RedisResult<Vec<String>>;
+
+    async fn push_set(&self, key: &str, member: &str) -> RedisResult<()>;
+    async fn remove_set(&self, key: &str, member: &str) -> RedisResult<()>;
+    async fn get_set(&self, key: &str) -> RedisResult<HashSet<String>>;
+    // replace the whole set, readers see the old or the new members but never a part of them
+    async fn replace_set(&self, key: &str, members: &[String]) -> RedisResult<()>;
+    async fn exist_in_set(&self, key: &str, member: &str) -> RedisResult<bool>;
+    // turn a list into a set of its values keeping the expiry, return false if the key holds no list
+    async fn convert_list_to_set(&self, key: &str) -> RedisResult<bool>;
+
+    async fn push_hash_expire(&self, key: &str, field: &str, value: &str, expire: i64) -> RedisResult<()>;
+    async fn push_hash(&self, key: &str, field: &str, value: &str) -> RedisResult<()>;
+    // set a field only if it still holds the current value, return true if set
+    async fn set_hash_if_equal(&self, key: &str, field: &str, current: &str, value: &str) -> RedisResult<bool>;
+    async fn get_all_hash(&self, key: &str) -> RedisResult<Vec<(String, String)>>;
+    async fn get_hash(&self, key: &str, field: &str) -> RedisResult<String>;
+    async fn remove_hash(&self, key: &str, field: &str) -> RedisResult<()>;
+
+    // add one (key, member) pair per sorted set with the same score and drop their members scored below min, as one batch
+    async fn push_sorted_sets(&self, members: &[(String, String)], score: i64, min: i64) -> RedisResult<()>;
+    async fn get_sorted_set_by_score(&self, key: &str, min: i64, max: i64) -> RedisResult<Vec<(String, i64)>>;
+
+    // get all exists keys matching a glob pattern
+    async fn get_all_keys(&self, key: &str) -> RedisResult<Vec<String>>;
+
+    // get all exists keys name in specific layer
+    async fn get_all_keys_name(&self, key: &str) -> RedisResult<Vec<String>> {
+        let keys: Vec<String> = self.get_all_keys(key).await?;
+        let mut keys_name: Vec<String> = Vec::new();
+        // calculate the number of layers in key
+        let key_layer = key.split(":").collect::<Vec<&str>>().len();
+        for key in keys.iter() {
+            let key = key.split(":").collect::<Vec<&str>>();
+            if key.len() < key_layer {
+                continue;
+            }
+            let key = key[key_layer - 1];
+            if !keys_name.iter().any(|name| name == key) {
+                keys_name.push(String::from(key));
+            }
+        }
+        RedisResult::Ok(keys_name)
+    }
+}
+
+// Build the store selected in config
+// kind: "redis" for the configured server, "memory" for a process local store,
+// "sqlite" for the database at path, "redis+sqlite" to keep only the durable keys in it
+pub fn from_profile(profile: &str) -> SharedStore {
+    let store_config = read_config_file(profile).store;
+    match store_config.kind.as_str() {
+        "memory" => Arc::new(MemoryStore::new(profile)),
+        "redis" => Arc::new(RedisInstance::new(profile)),
+        "sqlite" => Arc::new(SqliteStore::new(&store_config.path, profile)),
+        "redis+sqlite" => Arc::new(SplitStore::new(
+            Arc::new(RedisInstance::new(profile)),
+            Arc::new(SqliteStore::new(&store_config.path, profile)),
+        )),
+        other => {
+            tracing::error!("Unknown store {}, fallback to redis", other);
+            Arc::new(RedisInstance::new(profile))
+        }
+    }
+}
+
+// Process local store for development and tests, nothing survives a restart
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    profile: String,
+}
+
+struct Entry {
+    value: Value,
+    expire_at: Option<Instant>,
+}
+
+enum Value {
+    String(String),
+    List(Vec<String>),
+    Hash(Vec<(String, String)>),
+    Set(HashSet<String>),
+    SortedSet(Vec<(String, i64)>),
+}
+
+impl Entry {
+    fn new(value: Value) -> Entry {
+        Entry { value, expire_at: None }
+    }
+}
+
+impl MemoryStore {
+    pub fn new(profile: &str) -> MemoryStore {
+        MemoryStore {
+            entries: Mutex::new(HashMap::new()),
+            profile: String::from(profile),
+        }
+    }
+
+    // lock the entries with the expired ones dropped
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expire_at.is_none_or(|expire_at| expire_at > now));
+        entries
+    }
+
+    // run f on the list of a key, created if missing, dropped if left empty
+    fn with_list<T>(&self, key: &str, f: impl FnOnce(&mut Vec<String>) -> T) -> RedisResult<T> {
+        let mut entries = self.entries();
+        let entry = entries.entry(String::from(key)).or_insert_with(|| Entry::new(Value::List(Vec::new())));
+        let res = match &mut entry.value {
+            Value::List(list) => f(list),
+            _ => return Err(wrong_type()),
+        };
+        if matches!(&entry.value, Value::List(list) if list.is_empty()) {
+            entries.remove(key);
+        }
+        Ok(res)
+    }
+
+    fn with_hash<T>(&self, key: &str, f: impl FnOnce(&mut Vec<(String, String)>) -> T) -> RedisResult<T> {
+        let mut entries = self.entries();
+        let entry = entries.entry(String::from(key)).or_insert_with(|| Entry::new(Value::Hash(Vec::new())));
+        let res = match &mut entry.value {
+            Value::Hash(hash) => f(hash),
+            _ => return Err(wrong_type()),
+        };
+        if matches!(&entry.value, Value::Hash(hash) if hash.is_empty()) {
+            entries.remove(key);
+        }
+        Ok(res)
+    }
+
+    fn with_set<T>(&self, key: &str, f: impl FnOnce(&mut HashSet<String>) -> T) -> RedisResult<T> {
+        let mut entries = self.entries();
+        let entry = entries.entry(String::from(key)).or_insert_with(|| Entry::new(Value::Set(HashSet::new())));
+        let res = match &mut entry.value {
+            Value::Set(set) => f(set),
+            _ => return Err(wrong_type()),
+        };
+        if matches!(&entry.value, Value::Set(set) if set.is_empty()) {
+            entries.remove(key);
+        }
+        Ok(res)
+    }
+
+    fn with_sorted_set<T>(&self, key: &str, f: impl FnOnce(&mut Vec<(String, i64)>) -> T) -> RedisResult<T> {
+        let mut entries = self.entries();
+        let entry = entries.entry(String::from(key)).or_insert_with(|| Entry::new(Value::SortedSet(Vec::new())));
+        let res = match &mut entry.value {
+            Value::SortedSet(set) => f(set),
+            _ => return Err(wrong_type()),
+        };
+        if matches!(&entry.value, Value::SortedSet(set) if set.is_empty()) {
+            entries.remove(key);
+        }
+        Ok(res)
+    }
+
+    fn expire_in(&self, key: &str, expire: Duration) {
+        if let Some(entry) = self.entries().get_mut(key) {
+            entry.expire_at = Some(Instant::now() + expire);
+        }
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    async fn get(&self, key: &str) -> RedisResult<String> {
+        match self.entries().get(key).map(|entry| &entry.value) {
+            Some(Value::String(value)) => Ok(value.clone()),
+            Some(_) => Err(wrong_type()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &str) -> RedisResult<()> {
+        self.entries().insert(String::from(key), Entry::new(Value::String(String::from(value))));
+        Ok(())
+    }
+
+    async fn set_nx_px(&self, key: &str, value: &str, expire_ms: u64) -> RedisResult<bool> {
+        let mut entries = self.entries();
+        if entries.contains_key(key) {
+            return Ok(false);
+        }
+        let mut entry = Entry::new(Value::String(String::from(value)));
+        entry.expire_at = Some(Instant::now() + Duration::from_millis(expire_ms));
+        entries.insert(String::from(key), entry);
+        Ok(true)
+    }
+
+    async fn incr(&self, key: &str) -> RedisResult<i64> {
+        let mut entries = self.entries();
+        let entry = entries
+            .entry(String::from(key))
+            .or_insert_with(|| Entry::new(Value::String(String::from("0"))));
+        match &mut entry.value {
+            Value::String(value) => {
+                let counter = value.parse::<i64>().map_err(|_| wrong_type())? + 1;
+                *value = counter.to_string();
+                Ok(counter)
+            }
+            _ => Err(wrong_type()),
+        }
+    }
+
+    async fn set_expire(&self, key: &str, expire: i64) -> RedisResult<()> {
+        if expire <= 0 {
+            return self.delete(key).await;
+        }
+        self.expire_in(key, Duration::from_secs(expire as u64));
+        Ok(())
+    }
+
+    async fn persist(&self, key: &str) -> RedisResult<()> {
+        if let Some(entry) = self.entries().get_mut(key) {
+            entry.expire_at = None;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> RedisResult<()> {
+        self.entries().remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> RedisResult<bool> {
+        Ok(self.entries().contains_key(key))
+    }
+
+    async fn replace_list_expire(&self, key: &str, value: &[String], expire: i64) -> RedisResult<()> {
+        let mut entries = self.entries();
+        if value.is_empty() || expire <= 0 {
+            entries.remove(key);
+            return Ok(());
+        }
+        let mut entry = Entry::new(Value::List(value.to_vec()));
+        entry.expire_at = Some(Instant::now() + Duration::from_secs(expire as u64));
+        entries.insert(String::from(key), entry);
+        Ok(())
+    }
+
+    async fn get_list(&self, key: &str) -> RedisResult<Vec<String>> {
+        self.with_list(key, |list| list.clone())
+    }
+
+    async fn push_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        self.with_set(key, |set| {
+            set.insert(String::from(member));
+        })
+    }
+
+    async fn remove_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        self.with_set(key, |set| {
+            set.remove(member);
+        })
+    }
+
+    async fn get_set(&self, key: &str) -> RedisResult<HashSet<String>> {
+        self.with_set(key, |set| set.clone())
+    }
+
+    async fn replace_set(&self, key: &str, members: &[String]) -> RedisResult<()> {
+        let mut entries = self.entries();
+        if members.is_empty() {
+            entries.remove(key);
+            return Ok(());
+        }
+        entries.insert(String::from(key), Entry::new(Value::Set(members.iter().cloned().collect())));
+        Ok(())
+    }
+
+    async fn exist_in_set(&self, key: &str, member: &str) -> RedisResult<bool> {
+        self.with_set(key, |set| set.contains(member))
+    }
+
+    async fn convert_list_to_set(&self, key: &str) -> RedisResult<bool> {
+        let mut entries = self.entries();
+        let entry = match entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        if let Value::List(list) = &entry.value {
+            entry.value = Value::Set(list.iter().cloned().collect());
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn push_hash_expire(&self, key: &str, field: &str, value: &str, expire: i64) -> RedisResult<()> {
+        self.push_hash(key, field, value).await?;
+        self.set_expire(key, expire).await
+    }
+
+    async fn push_hash(&self, key: &str, field: &str, value: &str) -> RedisResult<()> {
+        self.with_hash(key, |hash| match hash.iter_mut().find(|(name, _)| name == field) {
+            Some((_, current)) => *current = String::from(value),
+            None => hash.push((String::from(field), String::from(value))),
+        })
+    }
+
+    async fn set_hash_if_equal(&self, key: &str, field: &str, current: &str, value: &str) -> RedisResult<bool> {
+        self.with_hash(key, |hash| match hash.iter_mut().find(|(name, _)| name == field) {
+            Some((_, stored)) if stored == current => {
+                *stored = String::from(value);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn get_all_hash(&self, key: &str) -> RedisResult<Vec<(String, String)>> {
+        self.with_hash(key, |hash| hash.clone())
+    }
+
+    async fn get_hash(&self, key: &str, field: &str) -> RedisResult<String> {
+        self.with_hash(key, |hash| hash.iter().find(|(name, _)| name == field).map(|(_, value)| value.clone()))?
+            .ok_or_else(|| not_found(key))
+    }
+
+    async fn remove_hash(&self, key: &str, field: &str) -> RedisResult<()> {
+        self.with_hash(key, |hash| hash.retain(|(name, _)| name != field))
+    }
+
+    async fn push_sorted_sets(&self, members: &[(String, String)], score: i64, min: i64) -> RedisResult<()> {
+        for (key, member) in members {
+            self.with_sorted_set(key, |set| {
+                set.retain(|(name, member_score)| name != member && *member_score >= min);
+                set.push((member.clone(), score));
+                set.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn get_sorted_set_by_score(&self, key: &str, min: i64, max: i64) -> RedisResult<Vec<(String, i64)>> {
+        self.with_sorted_set(key, |set| {
+            set.iter().filter(|(_, score)| *score >= min && *score <= max).cloned().collect()
+        })
+    }
+
+    async fn get_all_keys(&self, key: &str) -> RedisResult<Vec<String>> {
+        Ok(self.entries().keys().filter(|name| glob_match(key, name)).cloned().collect())
+    }
+}
+
+// Refreshed data and leases in the cache store, configuration and records in the durable one
+pub struct SplitStore {
+    cache: SharedStore,
+    durable: SharedStore,
+}
+
+impl SplitStore {
+    pub fn new(cache: SharedStore, durable: SharedStore) -> SplitStore {
+        SplitStore { cache, durable }
+    }
+
+    fn route(&self, key: &str) -> &dyn Store {
+        if is_durable(key) {
+            self.durable.as_ref()
+        } else {
+            self.cache.as_ref()
+        }
+    }
+}
+
+// Alerts, jobs, reminders, history, filters, skip lists and thresholds outlive the cache
+fn is_durable(key: &str) -> bool {
+    matches!(key, "Alerts" | "Jobs" | "Reminders")
+        || key.starts_with("History:")
+        || key.split(':').any(|part| matches!(part, "filter" | "skip" | "threshold"))
+}
+
+#[async_trait]
+impl Store for SplitStore {
+    fn profile(&self) -> &str {
+        self.cache.profile()
+    }
+
+    fn cache(&self) -> Option<&dyn Store> {
+        Some(self.cache.as_ref())
+    }
+
+    async fn get(&self, key: &str) -> RedisResult<String> {
+        self.route(key).get(key).await
+    }
+
+    async fn set(&self, key: &str, value: &str) -> RedisResult<()> {
+        self.route(key).set(key, value).await
+    }
+
+    async fn set_nx_px(&self, key: &str, value: &str, expire_ms: u64) -> RedisResult<bool> {
+        self.route(key).set_nx_px(key, value, expire_ms).await
+    }
+
+    async fn incr(&self, key: &str) -> RedisResult<i64> {
+        self.route(key).incr(key).await
+    }
+
+    async fn set_expire(&self, key: &str, expire: i64) -> RedisResult<()> {
+        self.route(key).set_expire(key, expire).await
+    }
+
+    async fn persist(&self, key: &str) -> RedisResult<()> {
+        self.route(key).persist(key).await
+    }
+
+    async fn delete(&self, key: &str) -> RedisResult<()> {
+        self.route(key).delete(key).await
+    }
+
+    async fn exists(&self, key: &str) -> RedisResult<bool> {
+        self.route(key).exists(key).await
+    }
+
+    async fn replace_list_expire(&self, key: &str, value: &[String], expire: i64) -> RedisResult<()> {
+        self.route(key).replace_list_expire(key, value, expire).await
+    }
+
+    async fn get_list(&self, key: &str) -> RedisResult<Vec<String>> {
+        self.route(key).get_list(key).await
+    }
+
+    async fn push_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        self.route(key).push_set(key, member).await
+    }
+
+    async fn remove_set(&self, key: &str, member: &str) -> RedisResult<()> {
+        self.route(key).remove_set(key, member).await
+    }
+
+    async fn get_set(&self, key: &str) -> RedisResult<HashSet<String>> {
+        self.route(key).get_set(key).await
+    }
+
+    async fn replace_set(&self, key: &str, members: &[String]) -> RedisResult<()> {
+        self.route(key).replace_set(key, members).await
+    }
+
+    async fn exist_in_set(&self, key: &str, member: &str) -> RedisResult<bool> {
+        self.route(key).exist_in_set(key, member).await
+    }
+
+    async fn convert_list_to_set(&self, key: &str) -> RedisResult<bool> {
+        self.route(key).convert_list_to_set(key).await
+    }
+
+    async fn push_hash_expire(&self, key: &str, field: &str, value: &str, expire: i64) -> RedisResult<()> {
+        self.route(key).push_hash_expire(key, field, value, expire).await
+    }
+
+    async fn push_hash(&self, key: &str, field: &str, value: &str) -> RedisResult<()> {
+        self.route(key).push_hash(key, field, value).await
+    }
+
+    async fn set_hash_if_equal(&self, key: &str, field: &str, current: &str, value: &str) -> RedisResult<bool> {
+        self.route(key).set_hash_if_equal(key, field, current, value).await
+    }
+
+    async fn get_all_hash(&self, key: &str) -> RedisResult<Vec<(String, String)>> {
+        self.route(key).get_all_hash(key).await
+    }
+
+    async fn get_hash(&self, key: &str, field: &str) -> RedisResult<String> {
+        self.route(key).get_hash(key, field).await
+    }
+
+    async fn remove_hash(&self, key: &str, field: &str) -> RedisResult<()> {
+        self.route(key).remove_hash(key, field).await
+    }
+
+    async fn push_sorted_sets(&self, members: &[(String, String)], score: i64, min: i64) -> RedisResult<()> {
+        let (durable, cache): (Vec<(String, String)>, Vec<(String, String)>) =
+            members.iter().cloned().partition(|(key, _)| is_durable(key));
+        self.durable.push_sorted_sets(&durable, score, min).await?;
+        self.cache.push_sorted_sets(&cache, score, min).await
+    }
+
+    async fn get_sorted_set_by_score(&self, key: &str, min: i64, max: i64) -> RedisResult<Vec<(String, i64)>> {
+        self.route(key).get_sorted_set_by_score(key, min, max).await
+    }
+
+    // a pattern may match keys of both stores
+    async fn get_all_keys(&self, key: &str) -> RedisResult<Vec<String>> {
+        let mut keys = self.cache.get_all_keys(key).await?;
+        keys.retain(|key| !is_durable(key));
+        let durable = self.durable.get_all_keys(key).await?;
+        keys.extend(durable.into_iter().filter(|key| is_durable(key)));
+        Ok(keys)
+    }
+}
+
+pub(crate) fn wrong_type() -> RedisError {
+    RedisError::from((ErrorKind::TypeError, "Operation against a key holding the wrong kind of value"))
+}
+
+pub(crate) fn not_found(key: &str) -> RedisError {
+    RedisError::from((ErrorKind::TypeError, "Key not found", String::from(key)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // The behaviour every backend shares with Redis
+    pub(crate) async fn check_store(store: &dyn Store) {
+        assert!(store.get("Config").await.is_err());
+        store.set("Config", "{}").await.unwrap();
+        assert_eq!(store.get("Config").await.unwrap(), "{}");
+        // a replaced list keeps the order and holds only the new values, an empty one is dropped
+        store.replace_list_expire("Data:Currency:Fragment", &[String::from("old")], 60).await.unwrap();
+        store.replace_list_expire("Data:Currency:Fragment", &[String::from("a"), String::from("b")], 60).await.unwrap();
+        assert_eq!(store.get_list("Data:Currency:Fragment").await.unwrap(), vec![String::from("a"), String::from("b")]);
+        store.replace_list_expire("Data:Currency:Fragment", &[], 60).await.unwrap();
+        assert!(!store.exists("Data:Currency:Fragment").await.unwrap());
+        // sets hold each member once, a list converts in place
+        store.push_set("Item:skip", "Scarab").await.unwrap();
+        store.push_set("Item:skip", "Scarab").await.unwrap();
+        store.push_set("Item:skip", "Essence").await.unwrap();
+        store.remove_set("Item:skip", "Essence").await.unwrap();
+        assert_eq!(store.get_set("Item:skip").await.unwrap(), HashSet::from([String::from("Scarab")]));
+        assert!(store.exist_in_set("Item:skip", "Scarab").await.unwrap());
+        assert!(!store.exist_in_set("Currency:skip", "Fragment").await.unwrap());
+        assert!(!store.convert_list_to_set("Item:skip").await.unwrap());
+        store.replace_list_expire("Item:Scarab:filter", &[String::from("Titanic Scarab"), String::from("Titanic Scarab")], 60).await.unwrap();
+        assert!(store.convert_list_to_set("Item:Scarab:filter").await.unwrap());
+        store.persist("Item:Scarab:filter").await.unwrap();
+        assert_eq!(store.get_set("Item:Scarab:filter").await.unwrap(), HashSet::from([String::from("Titanic Scarab")]));
+        assert!(store.get_list("Item:Scarab:filter").await.is_err());
+        // a replaced set holds only the new members, an empty one is dropped
+        store.replace_set("Item:Scarab:filter", &[String::from("Gilded Scarab"), String::from("Gilded Scarab")]).await.unwrap();
+        assert_eq!(store.get_set("Item:Scarab:filter").await.unwrap(), HashSet::from([String::from("Gilded Scarab")]));
+        store.replace_set("Item:Scarab:filter", &[]).await.unwrap();
+        assert!(!store.exists("Item:Scarab:filter").await.unwrap());
+        store.delete("Item:Scarab:filter").await.unwrap();
+        store.delete("Item:skip").await.unwrap();
+        // a key of another kind is rejected
+        assert!(store.get_list("Config").await.is_err());
+        store.push_hash("Affliction:D2C", "Divine Orb", "250").await.unwrap();
+        store.push_hash("Affliction:D2C", "Divine Orb", "260").await.unwrap();
+        assert_eq!(store.get_hash("Affliction:D2C", "Divine Orb").await.unwrap(), "260");
+        assert!(store.get_hash("Affliction:D2C", "Chaos Orb").await.is_err());
+        assert!(!store.set_hash_if_equal("Affliction:D2C", "Divine Orb", "250", "270").await.unwrap());
+        assert!(store.set_hash_if_equal("Affliction:D2C", "Divine Orb", "260", "270").await.unwrap());
+        assert!(!store.set_hash_if_equal("Affliction:D2C", "Chaos Orb", "1", "1").await.unwrap());
+        assert_eq!(store.get_hash("Affliction:D2C", "Divine Orb").await.unwrap(), "270");
+        let divine = String::from("History:Affliction:Currency:Divine Orb");
+        let chaos = String::from("History:Affliction:Currency:Chaos Orb");
+        store.push_sorted_sets(&[(divine.clone(), String::from("a")), (chaos.clone(), String::from("x"))], 10, 0).await.unwrap();
+        store.push_sorted_sets(&[(divine.clone(), String::from("b"))], 20, 0).await.unwrap();
+        store.push_sorted_sets(&[(divine.clone(), String::from("c")), (chaos.clone(), String::from("y"))], 30, 15).await.unwrap();
+        store.push_sorted_sets(&[], 40, 15).await.unwrap();
+        assert_eq!(store.get_sorted_set_by_score(&divine, 0, 25).await.unwrap(), vec![(String::from("b"), 20)]);
+        assert_eq!(store.get_sorted_set_by_score(&chaos, 0, 40).await.unwrap(), vec![(String::from("y"), 30)]);
+        assert_eq!(store.incr("Lease:1:token").await.unwrap(), 1);
+        assert_eq!(store.incr("Lease:1:token").await.unwrap(), 2);
+        assert!(store.set_nx_px("Lease:1", "a", 60000).await.unwrap());
+        assert!(!store.set_nx_px("Lease:1", "b", 60000).await.unwrap());
+        assert_eq!(store.get("Lease:1").await.unwrap(), "a");
+        store.replace_list_expire("Data:Currency:Currency", &[String::from("{}")], 60).await.unwrap();
+        store.replace_list_expire("Data:Item:Scarab", &[String::from("{}")], 60).await.unwrap();
+        let mut names = store.get_all_keys_name("Data:*:*").await.unwrap();
+        names.sort();
+        assert_eq!(names, vec![String::from("Currency"), String::from("Scarab")]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        check_store(&MemoryStore::new("local")).await;
+    }
+
+    #[tokio::test]
+    async fn test_split_store() {
+        let cache: SharedStore = Arc::new(MemoryStore::new("local"));
+        let durable: SharedStore = Arc::new(MemoryStore::new("local"));
+        let store = SplitStore { cache: cache.clone(), durable: durable.clone() };
+        check_store(&store).await;
+        store.push_set("Item:Scarab:filter", "Titanic Scarab").await.unwrap();
+        store.replace_list_expire("Data:Item:Scarab", &[String::from("{}")], 60).await.unwrap();
+        assert!(durable.exists("Item:Scarab:filter").await.unwrap());
+        assert!(!cache.exists("Item:Scarab:filter").await.unwrap());
+        assert!(cache.exists("Data:Item:Scarab").await.unwrap());
+        let mut keys = store.get_all_keys("Item:*").await.unwrap();
+        keys.extend(store.get_all_keys("Data:*").await.unwrap());
+        keys.sort();
+        assert_eq!(keys, vec![String::from("Data:Currency:Currency"), String::from("Data:Item:Scarab"), String::from("Item:Scarab:filter")]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_expire() {
+        let store = MemoryStore::new("local");
+        assert!(store.set_nx_px("Lease:1", "a", 1).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(!store.exists("Lease:1").await.unwrap());
+        assert!(store.set_nx_px("Lease:1", "b", 60000).await.unwrap());
+        assert!(store.set_nx_px("Lease:2", "a", 1).await.unwrap());
+        store.persist("Lease:2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(store.exists("Lease:2").await.unwrap());
+    }
+}
@@ -0,0 +1,38 @@
+// Match a name or a key against a Redis style pattern, * and ? only
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, k));
+            p += 1;
+        } else if let Some((star_p, star_k)) = star {
+            p = star_p + 1;
+            k = star_k + 1;
+            star = Some((star_p, star_k + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("Data:*", "Data:Currency:Currency"));
+        assert!(glob_match("*:*:filter", "Item:Scarab:filter"));
+        assert!(glob_match("Lease:?", "Lease:1"));
+        assert!(!glob_match("Data:*", "History:Affliction"));
+        assert!(!glob_match("*:filter", "Item:Scarab:skip"));
+        assert!(glob_match("* Scarab of *", "Polished Scarab of Divination"));
+    }
+}